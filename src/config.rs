@@ -0,0 +1,161 @@
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Server settings read from the environment, with a `.env` file filling in whatever the real
+/// environment doesn't set. Every value is optional, unset ones keep the `App` defaults.
+///
+/// | Variable | Example |
+/// |---|---|
+/// | `HOST` | `0.0.0.0`, `::` (IPv6 and IPv4) |
+/// | `PORT` | `4000` |
+/// | `MAX_BODY_SIZE`, `MAX_HEADER_SIZE` | `1048576`, `512K`, `2M` |
+/// | `MAX_REQUESTS_PER_CONNECTION` | `1000` |
+/// | `MIN_BODY_RATE` | `240` (bytes per second) |
+/// | `KEEP_ALIVE_TIMEOUT`, `HEADER_READ_TIMEOUT`, `BODY_READ_TIMEOUT`, `HANDLER_TIMEOUT`, `WRITE_TIMEOUT`, `SHUTDOWN_TIMEOUT` | `30`, `30s`, `500ms`, `2m` |
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub host: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub max_body_size: Option<usize>,
+    pub max_header_size: Option<usize>,
+    pub max_requests_per_connection: Option<usize>,
+    pub min_body_rate: Option<u64>,
+    pub keep_alive_timeout: Option<Duration>,
+    pub header_read_timeout: Option<Duration>,
+    pub body_read_timeout: Option<Duration>,
+    pub handler_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub shutdown_timeout: Option<Duration>,
+}
+
+impl Config {
+    /// Reads the environment and a `.env` file in the working directory, if there is one.
+    pub fn from_env() -> Result<Self> {
+        let path = Path::new(".env");
+        if path.exists() {
+            Self::from_env_file(path)
+        } else {
+            Self::from_vars(&HashMap::new())
+        }
+    }
+
+    /// Reads the environment and the given `.env` file, which has to exist.
+    pub fn from_env_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|err| Error::Config {
+            key: path.display().to_string(),
+            message: err.to_string(),
+        })?;
+        Self::from_vars(&parse_env_file(&contents))
+    }
+
+    fn from_vars(file: &HashMap<String, String>) -> Result<Self> {
+        let var = |key: &str| std::env::var(key).ok().or_else(|| file.get(key).cloned());
+        Ok(Self {
+            host: parse(&var, "HOST", |value| IpAddr::from_str(value).ok())?,
+            port: parse(&var, "PORT", |value| value.parse().ok())?,
+            max_body_size: parse(&var, "MAX_BODY_SIZE", parse_size)?,
+            max_header_size: parse(&var, "MAX_HEADER_SIZE", parse_size)?,
+            max_requests_per_connection: parse(&var, "MAX_REQUESTS_PER_CONNECTION", |value| {
+                value.parse().ok().filter(|max| *max > 0)
+            })?,
+            min_body_rate: parse(&var, "MIN_BODY_RATE", |value| value.parse().ok())?,
+            keep_alive_timeout: parse(&var, "KEEP_ALIVE_TIMEOUT", parse_duration)?,
+            header_read_timeout: parse(&var, "HEADER_READ_TIMEOUT", parse_duration)?,
+            body_read_timeout: parse(&var, "BODY_READ_TIMEOUT", parse_duration)?,
+            handler_timeout: parse(&var, "HANDLER_TIMEOUT", parse_duration)?,
+            write_timeout: parse(&var, "WRITE_TIMEOUT", parse_duration)?,
+            shutdown_timeout: parse(&var, "SHUTDOWN_TIMEOUT", parse_duration)?,
+        })
+    }
+
+    /// The address to pass to `App::listen`, `0.0.0.0:4000` unless `HOST` or `PORT` say otherwise.
+    pub fn address(&self) -> (IpAddr, u16) {
+        (
+            self.host.unwrap_or(IpAddr::from([0, 0, 0, 0])),
+            self.port.unwrap_or(4000),
+        )
+    }
+}
+
+fn parse<T>(
+    var: &impl Fn(&str) -> Option<String>,
+    key: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<Option<T>> {
+    let Some(value) = var(key) else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    parse(value).map(Some).ok_or_else(|| Error::Config {
+        key: key.to_owned(),
+        message: format!("`{}` is not a valid value", value),
+    })
+}
+
+/// `KEY=value` lines, ignoring blank lines and `#` comments. Values may be quoted and lines
+/// may start with `export`, like in a shell script.
+fn parse_env_file(contents: &str) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| {
+                value
+                    .strip_prefix(*quote)
+                    .and_then(|value| value.strip_suffix(*quote))
+            })
+            .unwrap_or(value);
+        vars.insert(key.trim().to_owned(), value.to_owned());
+    }
+    vars
+}
+
+/// Whole seconds, or a number with an `ms`, `s`, `m` or `h` suffix.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    match unit.trim() {
+        "" | "s" => Some(Duration::from_secs(number)),
+        "ms" => Some(Duration::from_millis(number)),
+        "m" => Some(Duration::from_secs(number.checked_mul(60)?)),
+        "h" => Some(Duration::from_secs(number.checked_mul(3600)?)),
+        _ => None,
+    }
+}
+
+/// Bytes, or a number with a `K`, `M` or `G` suffix (powers of 1024).
+fn parse_size(value: &str) -> Option<usize> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: usize = number.parse().ok()?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.checked_mul(multiplier)
+}
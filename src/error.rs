@@ -0,0 +1,50 @@
+use std::fmt;
+use std::io;
+
+/// Errors that stop the server from starting or running.
+#[derive(Debug)]
+pub enum Error {
+    /// The listening socket could not be bound, usually because the address is in use
+    /// or needs privileges.
+    Bind {
+        address: String,
+        source: io::Error,
+    },
+    /// A configuration value is missing or malformed.
+    Config {
+        key: String,
+        message: String,
+    },
+    /// A certificate, key or CA file could not be loaded.
+    Tls(String),
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bind { address, source } => write!(f, "Failed to bind {}: {}", address, source),
+            Error::Config { key, message } => write!(f, "Invalid config {}: {}", key, message),
+            Error::Tls(message) => write!(f, "TLS error: {}", message),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind { source, .. } => Some(source),
+            Error::Config { .. } | Error::Tls(_) => None,
+            Error::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -1,15 +1,47 @@
+use futures::stream::{BoxStream, StreamExt};
+use std::future::Future;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
-use crate::utils::{request::parse_request_data, response::handle_response, router::Router};
+use tokio::net::ToSocketAddrs;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::listener::{Incoming, Listener};
+use crate::tls::{TlsAcceptor, TlsConfig};
+#[cfg(unix)]
+use crate::unix::UnixSocket;
+use crate::utils::{
+    connection::{is_disconnect, with_timeout, Connection, Limits, ReadError, Stream},
+    request::Request,
+    response::Response,
+    router::{write_response, Router},
+    session::Sessions,
+};
+#[cfg(feature = "http2")]
+use crate::utils::http2;
+
+type Endpoints = Arc<dyn Fn(&mut Router) + Send + Sync>;
 
 pub struct App {
-    endpoints: Option<Arc<dyn Fn(&mut Router) + Send + Sync>>,
+    endpoints: Option<Endpoints>,
+    sessions: Option<Arc<Sessions>>,
+    limits: Limits,
+    shutdown_timeout: Duration,
+    tls: Option<TlsConfig>,
 }
 
 impl App {
     pub fn new() -> Self {
         Self {
             endpoints: None,
+            sessions: None,
+            limits: Limits::default(),
+            shutdown_timeout: Duration::from_secs(10),
+            tls: None,
         }
     }
 
@@ -20,35 +52,482 @@ impl App {
         self.endpoints = Some(Arc::new(endpoints));
     }
 
-    pub async fn run(self, port: i128) {
-        let listener = tokio::net::TcpListener::bind(&format!("0.0.0.0:{port}"))
+    pub fn sessions(&mut self, sessions: Sessions) {
+        self.sessions = Some(Arc::new(sessions));
+    }
+
+    /// How long an idle keep-alive connection is held open waiting for the next request.
+    /// Defaults to 5 seconds.
+    pub fn keep_alive_timeout(&mut self, timeout: Duration) {
+        self.limits.keep_alive = timeout;
+    }
+
+    /// Largest request body accepted, bigger ones get `413 Content Too Large`. Defaults to 1 MiB.
+    pub fn max_body_size(&mut self, bytes: usize) {
+        self.limits.max_body = bytes;
+    }
+
+    /// Largest request line plus headers accepted, bigger ones get
+    /// `431 Request Header Fields Too Large`. Defaults to 16 KiB.
+    pub fn max_header_size(&mut self, bytes: usize) {
+        self.limits.max_head = bytes;
+    }
+
+    /// Applies the limits and timeouts set in the config, leaving the others as they are.
+    /// The address is not part of this, pass `config.address()` to `listen`.
+    pub fn configure(&mut self, config: &Config) {
+        if let Some(bytes) = config.max_body_size {
+            self.max_body_size(bytes);
+        }
+        if let Some(bytes) = config.max_header_size {
+            self.max_header_size(bytes);
+        }
+        if let Some(max_requests) = config.max_requests_per_connection {
+            self.max_requests_per_connection(max_requests);
+        }
+        if let Some(rate) = config.min_body_rate {
+            self.min_body_rate(rate);
+        }
+        if let Some(timeout) = config.keep_alive_timeout {
+            self.keep_alive_timeout(timeout);
+        }
+        if let Some(timeout) = config.header_read_timeout {
+            self.header_read_timeout(timeout);
+        }
+        if let Some(timeout) = config.body_read_timeout {
+            self.body_read_timeout(timeout);
+        }
+        if let Some(timeout) = config.handler_timeout {
+            self.handler_timeout(timeout);
+        }
+        if let Some(timeout) = config.write_timeout {
+            self.write_timeout(timeout);
+        }
+        if let Some(timeout) = config.shutdown_timeout {
+            self.shutdown_timeout(timeout);
+        }
+    }
+
+    /// Requests served on one connection before it is closed. Defaults to 1000.
+    pub fn max_requests_per_connection(&mut self, max_requests: usize) {
+        self.limits.max_requests = max_requests.max(1);
+    }
+
+    /// Time a client gets to send the request line and headers once it starts a request.
+    /// Slower clients get `408 Request Timeout`. Defaults to 10 seconds.
+    pub fn header_read_timeout(&mut self, timeout: Duration) {
+        self.limits.header_timeout = timeout;
+    }
+
+    /// Time a client gets to send the whole request body. Defaults to 60 seconds.
+    pub fn body_read_timeout(&mut self, timeout: Duration) {
+        self.limits.body_timeout = timeout;
+    }
+
+    /// Average rate, in bytes per second, request bodies must arrive at after a 5 second grace
+    /// period. Slower clients get `408 Request Timeout`. Defaults to 240, `0` disables the check.
+    pub fn min_body_rate(&mut self, bytes_per_second: u64) {
+        self.limits.min_body_rate = bytes_per_second;
+    }
+
+    /// Time a handler may take to produce its response before the client gets
+    /// `503 Service Unavailable` instead. Streamed bodies are not counted. Off by default.
+    pub fn handler_timeout(&mut self, timeout: Duration) {
+        self.limits.handler_timeout = Some(timeout);
+    }
+
+    /// Time a write may wait for the client to read, after which the connection is dropped.
+    /// Defaults to 30 seconds.
+    pub fn write_timeout(&mut self, timeout: Duration) {
+        self.limits.write_timeout = timeout;
+    }
+
+    /// How long in-flight requests get to finish once shutdown starts, after which their
+    /// connections are dropped. Defaults to 10 seconds.
+    pub fn shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Serves HTTPS instead of plain HTTP on whatever address the app listens on.
+    pub fn tls(&mut self, config: TlsConfig) {
+        self.tls = Some(config);
+    }
+
+    /// Serves on all IPv4 interfaces until the process gets SIGTERM or SIGINT (Ctrl+C),
+    /// then shuts down gracefully.
+    pub async fn run(self, port: i128) -> Result<()> {
+        self.run_with_shutdown(port, shutdown_signal()).await
+    }
+
+    /// Like `run`, stopping when `shutdown` completes instead of on a signal.
+    pub async fn run_with_shutdown<F>(self, port: i128, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let port = u16::try_from(port).map_err(|_| Error::Config {
+            key: "port".to_owned(),
+            message: format!("{} is not a valid port", port),
+        })?;
+        self.listen_with_shutdown((Ipv4Addr::UNSPECIFIED, port), shutdown)
+            .await
+    }
+
+    /// Like `run`, serving HTTPS with the certificate chain and private key in these PEM files.
+    /// For more hostnames or client certificates, pass a `TlsConfig` to `tls` instead.
+    pub async fn run_tls(
+        mut self,
+        port: i128,
+        cert: impl Into<PathBuf>,
+        key: impl Into<PathBuf>,
+    ) -> Result<()> {
+        self.tls(TlsConfig::new(cert, key));
+        self.run(port).await
+    }
+
+    /// Serves on the given address, e.g. `"127.0.0.1:4000"`, `("::", 4000)` or a `SocketAddr`,
+    /// until the process gets SIGTERM or SIGINT. The IPv6 wildcard `::` accepts IPv4 clients too.
+    pub async fn listen(self, address: impl ToSocketAddrs) -> Result<()> {
+        self.listen_with_shutdown(address, shutdown_signal()).await
+    }
+
+    /// Serves until `shutdown` completes. Then no new connections are accepted, idle keep-alive
+    /// connections are closed, and requests already being handled get `shutdown_timeout` to
+    /// finish before this returns.
+    pub async fn listen_with_shutdown<F>(
+        self,
+        address: impl ToSocketAddrs,
+        shutdown: F,
+    ) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let listener = Listener::bind(address).await?;
+        self.serve(vec![listener], shutdown).await
+    }
+
+    /// Serves on a listener that is already open, e.g. one passed by systemd socket activation
+    /// or inherited from the server being replaced, until the process gets SIGTERM or SIGINT.
+    pub async fn listen_on(self, listener: Listener) -> Result<()> {
+        self.listen_on_with_shutdown(listener, shutdown_signal())
             .await
-            .expect("Error while binding connection to public address");
-        println!("Server is running at: http://localhost:{}", port);
+    }
+
+    /// Like `listen_on`, stopping when `shutdown` completes instead of on a signal.
+    pub async fn listen_on_with_shutdown<F>(self, listener: Listener, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        self.serve(vec![listener], shutdown).await
+    }
+
+    /// Serves on all the listeners at once, e.g. a public port, an admin port that only serves
+    /// `/admin` and a Unix socket, until the process gets SIGTERM or SIGINT. Each can have its
+    /// own TLS config, and `Request::listener` tells which one a request came in on.
+    pub async fn listen_all(self, listeners: impl IntoIterator<Item = Listener>) -> Result<()> {
+        self.listen_all_with_shutdown(listeners, shutdown_signal())
+            .await
+    }
+
+    /// Like `listen_all`, stopping every listener when `shutdown` completes instead of on a
+    /// signal.
+    pub async fn listen_all_with_shutdown<F>(
+        self,
+        listeners: impl IntoIterator<Item = Listener>,
+        shutdown: F,
+    ) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        self.serve(listeners.into_iter().collect(), shutdown).await
+    }
+
+    /// Serves on a Unix domain socket until the process gets SIGTERM or SIGINT. The socket
+    /// file is removed again on shutdown. Requests carry the `peer_credentials` of the client.
+    #[cfg(unix)]
+    pub async fn listen_unix(self, socket: UnixSocket) -> Result<()> {
+        self.listen_unix_with_shutdown(socket, shutdown_signal())
+            .await
+    }
+
+    /// Like `listen_unix`, stopping when `shutdown` completes instead of on a signal.
+    #[cfg(unix)]
+    pub async fn listen_unix_with_shutdown<F>(self, socket: UnixSocket, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let listener = Listener::unix(socket)?;
+        self.serve(vec![listener], shutdown).await
+    }
+
+    /// Loads the certificates before any connection is accepted, so a bad one fails startup
+    /// right away.
+    fn tls_acceptor(&self, listener: &Listener) -> Result<Option<Arc<TlsAcceptor>>> {
+        match listener.tls.as_ref().or(self.tls.as_ref()) {
+            Some(config) => Ok(Some(Arc::new(TlsAcceptor::new(config.clone())?))),
+            None => Ok(None),
+        }
+    }
+
+    async fn serve<F>(self, listeners: Vec<Listener>, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        if listeners.is_empty() {
+            return Err(Error::Config {
+                key: "listeners".to_owned(),
+                message: "there is nothing to listen on".to_owned(),
+            });
+        }
+        // Aborted when dropped on return.
+        let mut reloader = JoinSet::new();
+        let router = Arc::new(self.router(None));
+        let mut serving = Vec::new();
+        for mut listener in listeners {
+            let tls = self.tls_acceptor(&listener)?;
+            if let Some(ref tls) = tls {
+                reloader.spawn(Arc::clone(tls).watch());
+            }
+            let router = match listener.routes.take() {
+                Some(prefixes) => Arc::new(self.router(Some(prefixes))),
+                None => Arc::clone(&router),
+            };
+            let name = listener.name.take();
+            let incoming = listener.incoming()?;
+            match name {
+                Some(ref name) => println!(
+                    "Server is running at: {} ({})",
+                    incoming.address(tls.is_some()),
+                    name
+                ),
+                None => println!("Server is running at: {}", incoming.address(tls.is_some())),
+            }
+            serving.push(Serving {
+                name: name.unwrap_or_else(|| incoming.local_address()).into(),
+                incoming,
+                tls,
+                router,
+            });
+        }
 
         let app_ref = Arc::new(self);
+        let (stop, stopping) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        let mut accepting = futures::stream::select_all(serving.iter().map(Serving::accept));
+        tokio::pin!(shutdown);
         loop {
-            let (mut stream, _) = listener
-                .accept()
-                .await
-                .expect("Failed to accept the client's established connection");
-            let app = Arc::clone(&app_ref);
-            tokio::spawn(async move {
-                app.handle_stream(&mut stream).await;
-            });
+            tokio::select! {
+                _ = &mut shutdown => break,
+                Some((listener, stream)) = accepting.next() => {
+                    let app = Arc::clone(&app_ref);
+                    let router = Arc::clone(&listener.router);
+                    let name = Arc::clone(&listener.name);
+                    let stopping = stopping.clone();
+                    let acceptor = listener.tls.as_ref().map(|tls| tls.acceptor());
+                    connections.spawn(async move {
+                        let stream = match acceptor {
+                            Some(acceptor) => {
+                                let handshake = tokio::time::timeout(
+                                    app.limits.header_timeout,
+                                    acceptor.accept(stream),
+                                );
+                                match handshake.await {
+                                    Ok(Ok(stream)) => Stream::Tls(Box::new(stream)),
+                                    // Mostly clients that don't trust the certificate, or scanners.
+                                    _ => return,
+                                }
+                            }
+                            None => stream,
+                        };
+                        app.handle_stream(Connection::new(stream, name), &router, stopping)
+                            .await;
+                    });
+                }
+                // Reap finished connections so the set doesn't grow forever.
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            }
+        }
+
+        drop(accepting);
+        for listener in serving {
+            listener.incoming.close();
         }
+        let _ = stop.send(true);
+        if !connections.is_empty() {
+            println!(
+                "Shutting down, waiting for {} connections to finish",
+                connections.len()
+            );
+        }
+        let drained = tokio::time::timeout(app_ref.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            connections.shutdown().await;
+        }
+        Ok(())
     }
 
-    async fn handle_stream(&self, stream: &mut tokio::net::TcpStream) {
-        let request = parse_request_data(stream).await;
+    fn router(&self, prefixes: Option<Vec<String>>) -> Router {
         let mut router = Router::new();
+        router.sessions = self.sessions.clone();
+        router.prefixes = prefixes;
 
         // Configure routes via callback
         if let Some(ref endpoints) = self.endpoints {
             endpoints(&mut router);
         }
+        router
+    }
+
+    /// Serves requests on the connection one after the other until either side closes it.
+    /// Pipelined requests are answered in order.
+    async fn handle_stream(
+        &self,
+        mut connection: Connection,
+        router: &Router,
+        mut stopping: watch::Receiver<bool>,
+    ) {
+        let mut served = 0;
+        loop {
+            // Idle connections are closed right away on shutdown, busy ones after their response.
+            let ready = tokio::select! {
+                ready = connection.wait_for_request(self.limits.keep_alive) => ready,
+                _ = stopping.wait_for(|stop| *stop) => false,
+            };
+            if !ready {
+                break;
+            }
+            #[cfg(feature = "http2")]
+            if served == 0 && connection.is_http2(&self.limits).await {
+                return http2::serve(connection, router, &self.limits, stopping, None).await;
+            }
+
+            let request = match connection.read_request(&self.limits).await {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(ReadError::Io(err)) => {
+                    if !is_disconnect(&err) {
+                        eprintln!("Error while reading request: {}", err);
+                    }
+                    break;
+                }
+                Err(ReadError::Status(status)) => {
+                    let response = Response::new().error(status.reason_phrase(), status);
+                    write_response(
+                        connection.stream(),
+                        response,
+                        &Request::default(),
+                        false,
+                        self.limits.write_timeout,
+                    )
+                    .await;
+                    break;
+                }
+            };
+            served += 1;
+
+            #[cfg(feature = "http2")]
+            match connection.upgrade_h2c(&request, &self.limits).await {
+                Ok(true) => {
+                    return http2::serve(connection, router, &self.limits, stopping, Some(request))
+                        .await
+                }
+                Ok(false) => {}
+                Err(_) => break,
+            }
+
+            let response = router.respond(&request, self.limits.handler_timeout).await;
+            // Checked after the handler, so a response finishing during shutdown says it's the last.
+            let keep_alive =
+                request.keep_alive() && served < self.limits.max_requests && !*stopping.borrow();
+            if !write_response(
+                connection.stream(),
+                response,
+                &request,
+                keep_alive,
+                self.limits.write_timeout,
+            )
+            .await
+            {
+                break;
+            }
+        }
+        let _ = with_timeout(self.limits.write_timeout, connection.stream().shutdown()).await;
+    }
+}
+
+/// A listener with what its connections are served with.
+struct Serving {
+    incoming: Incoming,
+    name: Arc<str>,
+    tls: Option<Arc<TlsAcceptor>>,
+    router: Arc<Router>,
+}
+
+impl Serving {
+    /// The connections coming in, waiting out errors that would just happen again.
+    fn accept(&self) -> BoxStream<'_, (&Serving, Stream)> {
+        futures::stream::unfold(None, move |mut backoff| async move {
+            loop {
+                if let Some(delay) = backoff {
+                    tokio::time::sleep(delay).await;
+                }
+                match self.incoming.accept().await {
+                    Ok(stream) => return Some(((self, stream), None)),
+                    Err(err) => {
+                        backoff = accept_backoff(&err, backoff);
+                        eprintln!("Failed to accept connection: {}", err);
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+/// How long to wait before accepting again. Errors about a single aborted connection are
+/// retried right away, running out of file descriptors or memory backs off exponentially
+/// up to a second instead of spinning on an error that will just happen again.
+fn accept_backoff(err: &std::io::Error, previous: Option<Duration>) -> Option<Duration> {
+    use std::io::ErrorKind;
+    if matches!(
+        err.kind(),
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted
+    ) {
+        return None;
+    }
+    let delay = previous.map_or(Duration::from_millis(5), |delay| delay * 2);
+    Some(delay.min(Duration::from_secs(1)))
+}
+
+async fn shutdown_signal() {
+    let interrupt = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
 
-        handle_response(stream, &request, &router).await;
-        stream.flush().await.expect("Failed to flush stream");
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -1,2 +1,10 @@
 pub mod utils;
 pub mod init;
+pub mod config;
+pub mod tls;
+pub mod listener;
+#[cfg(unix)]
+pub mod unix;
+pub mod error;
+
+pub use error::{Error, Result};
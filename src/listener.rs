@@ -0,0 +1,286 @@
+use crate::error::{Error, Result};
+use crate::tls::TlsConfig;
+#[cfg(unix)]
+use crate::unix::UnixSocket;
+use crate::utils::connection::Stream;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::net::ToSocketAddrs;
+
+/// The first file descriptor systemd passes, the ones after it follow in order.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Set once the sockets from systemd have been taken.
+#[cfg(unix)]
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// A listening socket for `App::listen_on` and `App::listen_all`, either bound by the app or
+/// handed to it already open, which lets a new process take over from the old one without
+/// refusing connections.
+///
+/// ```no_run
+/// use rust_express::listener::Listener;
+///
+/// # async fn example() -> rust_express::Result<()> {
+/// let listener = match Listener::systemd()?.pop() {
+///     Some(listener) => listener,
+///     None => Listener::bind(("::", 8080)).await?,
+/// };
+/// # Ok(())
+/// # }
+/// ```
+pub struct Listener {
+    kind: Kind,
+    pub(crate) name: Option<String>,
+    pub(crate) tls: Option<TlsConfig>,
+    pub(crate) routes: Option<Vec<String>>,
+}
+
+enum Kind {
+    Tcp(std::net::TcpListener),
+    /// With the path to remove on shutdown, for sockets the app created itself.
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// Binds the first address that works out of those `address` resolves to. The IPv6
+    /// wildcard `::` accepts IPv4 clients too.
+    pub async fn bind(address: impl ToSocketAddrs) -> Result<Self> {
+        let mut last_error = None;
+        for address in tokio::net::lookup_host(address).await? {
+            match bind_socket(address) {
+                Ok(listener) => return Ok(Self::new(Kind::Tcp(listener))),
+                Err(source) => {
+                    last_error = Some(Error::Bind {
+                        address: address.to_string(),
+                        source,
+                    })
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Address did not resolve to anything",
+            ))
+        }))
+    }
+
+    /// Binds a Unix domain socket. Its file is removed again on shutdown.
+    #[cfg(unix)]
+    pub fn unix(socket: UnixSocket) -> Result<Self> {
+        let listener = socket.bind()?;
+        Ok(Self::new(Kind::Unix(
+            listener,
+            Some(socket.path().to_owned()),
+        )))
+    }
+
+    /// Serves on a TCP listener bound elsewhere, e.g. with options this crate doesn't set.
+    pub fn from_std(listener: std::net::TcpListener) -> Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self::new(Kind::Tcp(listener)))
+    }
+
+    /// Serves on a listening TCP or Unix socket inherited from the parent process, e.g. the
+    /// old server during a restart. Inherited Unix socket files are left in place on shutdown.
+    ///
+    /// ```no_run
+    /// use rust_express::listener::Listener;
+    /// use std::os::fd::{FromRawFd, OwnedFd};
+    ///
+    /// # fn example() -> rust_express::Result<()> {
+    /// let fd: i32 = std::env::var("SERVER_FD").unwrap().parse().unwrap();
+    /// // Safety: the parent passed this fd for us to own.
+    /// let listener = Listener::from_fd(unsafe { OwnedFd::from_raw_fd(fd) })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(unix)]
+    pub fn from_fd(fd: OwnedFd) -> Result<Self> {
+        Self::from_socket(Socket::from(fd))
+    }
+
+    #[cfg(unix)]
+    fn from_socket(socket: Socket) -> Result<Self> {
+        let invalid =
+            |message: &str| Error::Io(io::Error::new(io::ErrorKind::InvalidInput, message));
+        if socket.r#type()? != Type::STREAM {
+            return Err(invalid("Inherited socket is not a stream socket"));
+        }
+        #[cfg(target_os = "linux")]
+        if !socket.is_listener()? {
+            return Err(invalid("Inherited socket is not listening"));
+        }
+        let address = socket.local_addr()?;
+        socket.set_nonblocking(true)?;
+        if address.as_socket().is_some() {
+            Ok(Self::new(Kind::Tcp(socket.into())))
+        } else if address.is_unix() {
+            Ok(Self::new(Kind::Unix(socket.into(), None)))
+        } else {
+            Err(invalid("Inherited socket is neither TCP nor Unix"))
+        }
+    }
+
+    /// Takes the sockets systemd passed to this process through socket activation
+    /// (`LISTEN_FDS`), in the order of the `.socket` unit and named after their
+    /// `FileDescriptorName`. Empty when the process wasn't socket activated, and for every call
+    /// but the first, since the sockets can only be owned once.
+    #[cfg(unix)]
+    pub fn systemd() -> Result<Vec<Self>> {
+        let Ok(pid) = std::env::var("LISTEN_PID") else {
+            return Ok(Vec::new());
+        };
+        // Set for another process, e.g. inherited through a shell script. This is also what
+        // keeps child processes off the sockets, so the variables don't need to be removed,
+        // which isn't sound while other threads may read the environment.
+        if pid.trim().parse::<u32>().ok() != Some(std::process::id()) {
+            return Ok(Vec::new());
+        }
+        let count = std::env::var("LISTEN_FDS").unwrap_or_default();
+        let count: RawFd = count.trim().parse().map_err(|_| Error::Config {
+            key: "LISTEN_FDS".to_owned(),
+            message: format!("{:?} is not a number of file descriptors", count),
+        })?;
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return Ok(Vec::new());
+        }
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        let mut names = names.split(':');
+
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+            .map(|fd| {
+                // Safety: systemd passed these to this process, and `TAKEN` makes sure they are
+                // only wrapped once, so nothing else owns or closes them.
+                let socket = unsafe { Socket::from_raw_fd(fd) };
+                socket.set_cloexec(true)?;
+                let listener = Self::from_socket(socket)?;
+                Ok(match names.next() {
+                    Some(name) if !name.is_empty() => listener.name(name),
+                    _ => listener,
+                })
+            })
+            .collect()
+    }
+
+    fn new(kind: Kind) -> Self {
+        Self {
+            kind,
+            name: None,
+            tls: None,
+            routes: None,
+        }
+    }
+
+    /// Tells requests from this listener apart in `Request::listener`. Defaults to the address
+    /// it listens on.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Serves HTTPS with this config, instead of the one passed to `App::tls`.
+    pub fn tls(mut self, config: TlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Only serves routes, static files and fallbacks under these prefixes, e.g. `/admin`.
+    /// Anything else is `404 Not Found` on this listener. By default everything is served.
+    pub fn routes(mut self, prefixes: &[&str]) -> Self {
+        self.routes = Some(prefixes.iter().map(|prefix| prefix.to_string()).collect());
+        self
+    }
+
+    /// Registers the socket with the runtime, which only works from inside it.
+    pub(crate) fn incoming(self) -> io::Result<Incoming> {
+        Ok(match self.kind {
+            Kind::Tcp(listener) => Incoming::Tcp(tokio::net::TcpListener::from_std(listener)?),
+            #[cfg(unix)]
+            Kind::Unix(listener, path) => {
+                Incoming::Unix(tokio::net::UnixListener::from_std(listener)?, path)
+            }
+        })
+    }
+}
+
+/// A listener ready to accept connections from.
+pub(crate) enum Incoming {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, Option<PathBuf>),
+}
+
+impl Incoming {
+    pub(crate) async fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Incoming::Tcp(listener) => Ok(Stream::Tcp(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Incoming::Unix(listener, _) => Ok(Stream::Unix(listener.accept().await?.0)),
+        }
+    }
+
+    /// Where clients connect, for the startup message.
+    pub(crate) fn address(&self, tls: bool) -> String {
+        let scheme = if tls { "https" } else { "http" };
+        match self {
+            Incoming::Tcp(_) => format!("{}://{}", scheme, self.local_address()),
+            #[cfg(unix)]
+            Incoming::Unix(..) => format!("{}+unix://{}", scheme, self.local_address()),
+        }
+    }
+
+    /// The address or socket path, which also names unnamed listeners.
+    pub(crate) fn local_address(&self) -> String {
+        let address = match self {
+            Incoming::Tcp(listener) => listener.local_addr().map(|address| address.to_string()),
+            #[cfg(unix)]
+            Incoming::Unix(listener, _) => {
+                listener
+                    .local_addr()
+                    .map(|address| match address.as_pathname() {
+                        Some(path) => path.display().to_string(),
+                        None => "(unnamed socket)".to_owned(),
+                    })
+            }
+        };
+        address.unwrap_or_else(|_| "(unknown address)".to_owned())
+    }
+
+    /// Stops listening, removing the socket file if the app created it.
+    pub(crate) fn close(self) {
+        #[cfg(unix)]
+        if let Incoming::Unix(_, Some(ref path)) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn bind_socket(address: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    // Lets a restarted server bind while old connections linger in TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    // The IPv6 wildcard takes IPv4 connections too, whatever the OS default is.
+    if address.is_ipv6() && address.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
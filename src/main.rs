@@ -1,9 +1,12 @@
+use rust_express::config::Config;
 use rust_express::init::App;
 use rust_express::utils::{response::Response, router::Router};
 
 #[tokio::main]
-async fn main() {
+async fn main() -> rust_express::Result<()> {
+    let config = Config::from_env()?;
     let mut app = App::new();
+    app.configure(&config);
 
     app.endpoints(move |router: &mut Router| {
         router.get("/", |_| {
@@ -11,5 +14,5 @@ async fn main() {
         });
     });
 
-    app.run(4000).await;
+    app.listen(config.address()).await
 }
@@ -0,0 +1,285 @@
+use crate::error::{Error, Result};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// Certificates and client authentication for serving HTTPS, passed to `App::tls`.
+///
+/// ```no_run
+/// use rust_express::tls::TlsConfig;
+///
+/// let tls = TlsConfig::new("certs/default.pem", "certs/default.key")
+///     .host("api.example.com", "certs/api.pem", "certs/api.key")
+///     .host("*.example.com", "certs/wildcard.pem", "certs/wildcard.key");
+/// ```
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    cert: PathBuf,
+    key: PathBuf,
+    hosts: Vec<(String, PathBuf, PathBuf)>,
+    client_ca: Option<PathBuf>,
+    client_auth_required: bool,
+    reload_interval: Duration,
+}
+
+impl TlsConfig {
+    /// The certificate chain and private key, both PEM files. This certificate is used for
+    /// clients that don't send a server name or ask for one no other certificate covers.
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+            hosts: Vec::new(),
+            client_ca: None,
+            client_auth_required: false,
+            reload_interval: Duration::from_secs(30),
+        }
+    }
+
+    /// Another certificate, picked when the client asks for `hostname` through SNI.
+    /// A hostname like `*.example.com` covers one level of subdomains.
+    pub fn host(
+        mut self,
+        hostname: &str,
+        cert: impl Into<PathBuf>,
+        key: impl Into<PathBuf>,
+    ) -> Self {
+        self.hosts
+            .push((hostname.to_ascii_lowercase(), cert.into(), key.into()));
+        self
+    }
+
+    /// Asks clients for a certificate signed by one of the CAs in this PEM file. Clients that
+    /// send none are still served unless `require_client_cert` is set, clients with a
+    /// certificate that doesn't verify are rejected. See `Request::client_certificate`.
+    pub fn client_ca(mut self, ca: impl Into<PathBuf>) -> Self {
+        self.client_ca = Some(ca.into());
+        self
+    }
+
+    /// Rejects clients without a valid certificate during the handshake. Needs `client_ca`.
+    pub fn require_client_cert(mut self, required: bool) -> Self {
+        self.client_auth_required = required;
+        self
+    }
+
+    /// How often the certificate files are checked for changes. Changed files are loaded
+    /// for new connections without a restart, if they fail to load the old ones are kept.
+    /// Defaults to 30 seconds, `Duration::ZERO` disables reloading.
+    pub fn reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    fn files(&self) -> Vec<&Path> {
+        let mut files = vec![self.cert.as_path(), self.key.as_path()];
+        for (_, cert, key) in &self.hosts {
+            files.push(cert);
+            files.push(key);
+        }
+        files.extend(self.client_ca.as_deref());
+        files
+    }
+
+    fn load(&self) -> Result<ServerConfig> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut hosts = HashMap::new();
+        for (hostname, cert, key) in &self.hosts {
+            hosts.insert(hostname.clone(), load_key(&provider, cert, key)?);
+        }
+        let certificates = Certificates {
+            default: load_key(&provider, &self.cert, &self.key)?,
+            hosts,
+        };
+
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_protocol_versions(rustls::DEFAULT_VERSIONS)
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        let builder = match self.client_ca {
+            Some(ref ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca)? {
+                    roots
+                        .add(cert)
+                        .map_err(|err| Error::Tls(format!("{}: {}", ca.display(), err)))?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = if self.client_auth_required {
+                    verifier
+                } else {
+                    verifier.allow_unauthenticated()
+                };
+                let verifier = verifier
+                    .build()
+                    .map_err(|err| Error::Tls(format!("{}: {}", ca.display(), err)))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_cert_resolver(Arc::new(certificates));
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        #[cfg(feature = "http2")]
+        config.alpn_protocols.insert(0, b"h2".to_vec());
+        Ok(config)
+    }
+}
+
+/// The certificate chain a client authenticated with, leaf first, as DER.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    chain: Arc<[CertificateDer<'static>]>,
+}
+
+impl ClientCertificate {
+    /// The client's own certificate, to be parsed with an X.509 crate of your choice.
+    pub fn der(&self) -> &[u8] {
+        &self.chain[0]
+    }
+
+    /// The client's certificate followed by any intermediates it sent.
+    pub fn chain(&self) -> impl Iterator<Item = &[u8]> {
+        self.chain.iter().map(|cert| cert.as_ref())
+    }
+}
+
+/// Hands out acceptors for the current certificates, reloading them when the files change.
+pub(crate) struct TlsAcceptor {
+    config: TlsConfig,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsAcceptor {
+    /// Loads the certificates right away, so a bad path or key fails startup.
+    pub(crate) fn new(config: TlsConfig) -> Result<Self> {
+        let current = RwLock::new(Arc::new(config.load()?));
+        Ok(Self { config, current })
+    }
+
+    pub(crate) fn acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        let config = self
+            .current
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        tokio_rustls::TlsAcceptor::from(Arc::clone(&config))
+    }
+
+    /// Polls the certificate files until the task is dropped.
+    pub(crate) async fn watch(self: Arc<Self>) {
+        let interval = self.config.reload_interval;
+        if interval.is_zero() {
+            return;
+        }
+        let mut modified = self.modified();
+        loop {
+            tokio::time::sleep(interval).await;
+            let now = self.modified();
+            if now == modified {
+                continue;
+            }
+            // Remembered even if loading fails, a half-written pair is retried once the
+            // second file changes too.
+            modified = now;
+            match self.config.load() {
+                Ok(config) => {
+                    *self
+                        .current
+                        .write()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(config);
+                    println!("Reloaded TLS certificates");
+                }
+                Err(err) => eprintln!(
+                    "Failed to reload TLS certificates, keeping the old ones: {}",
+                    err
+                ),
+            }
+        }
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.config
+            .files()
+            .iter()
+            .map(|file| {
+                std::fs::metadata(file)
+                    .and_then(|meta| meta.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// The client certificate of a finished handshake, if the client sent one.
+pub(crate) fn client_certificate(
+    connection: &rustls::ServerConnection,
+) -> Option<ClientCertificate> {
+    let chain = connection.peer_certificates()?;
+    if chain.is_empty() {
+        return None;
+    }
+    Some(ClientCertificate {
+        chain: chain.iter().map(|cert| cert.clone().into_owned()).collect(),
+    })
+}
+
+/// Picks a certificate by the server name the client sent.
+#[derive(Debug)]
+struct Certificates {
+    default: Arc<CertifiedKey>,
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = client_hello.server_name() else {
+            return Some(Arc::clone(&self.default));
+        };
+        let name = name.to_ascii_lowercase();
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        let key = self
+            .hosts
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| self.hosts.get(&wildcard)))
+            .unwrap_or(&self.default);
+        Some(Arc::clone(key))
+    }
+}
+
+fn load_key(provider: &CryptoProvider, cert: &Path, key: &Path) -> Result<Arc<CertifiedKey>> {
+    let certs = load_certs(cert)?;
+    let contents = read(key)?;
+    let private_key = rustls_pemfile::private_key(&mut contents.as_slice())
+        .map_err(|err| Error::Tls(format!("{}: {}", key.display(), err)))?
+        .ok_or_else(|| Error::Tls(format!("{}: no private key found", key.display())))?;
+    // Also checks that the key belongs to the certificate.
+    let certified = CertifiedKey::from_der(certs, private_key, provider)
+        .map_err(|err| Error::Tls(format!("{}: {}", key.display(), err)))?;
+    Ok(Arc::new(certified))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let contents = read(path)?;
+    let certs = rustls_pemfile::certs(&mut contents.as_slice())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|err| Error::Tls(format!("{}: {}", path.display(), err)))?;
+    if certs.is_empty() {
+        return Err(Error::Tls(format!(
+            "{}: no certificates found",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|err| Error::Tls(format!("{}: {}", path.display(), err)))
+}
@@ -0,0 +1,98 @@
+use crate::error::{Error, Result};
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// A Unix domain socket for `App::listen_unix` to serve on.
+///
+/// ```no_run
+/// use rust_express::unix::UnixSocket;
+///
+/// let socket = UnixSocket::new("/run/my-app/http.sock").mode(0o660);
+/// ```
+#[derive(Clone, Debug)]
+pub struct UnixSocket {
+    path: PathBuf,
+    mode: Option<u32>,
+    remove_stale: bool,
+}
+
+impl UnixSocket {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            mode: None,
+            remove_stale: true,
+        }
+    }
+
+    /// Permissions for the socket file, e.g. `0o660` to let the group connect.
+    /// Without this the process umask decides.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Removes a socket file left behind by a server that didn't shut down cleanly before
+    /// binding. Only sockets nobody is listening on are removed, never other files. On by default.
+    pub fn remove_stale(mut self, remove_stale: bool) -> Self {
+        self.remove_stale = remove_stale;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn bind(&self) -> Result<UnixListener> {
+        let bind_error = |source| Error::Bind {
+            address: self.path.display().to_string(),
+            source,
+        };
+        if self.remove_stale {
+            remove_stale(&self.path).map_err(bind_error)?;
+        }
+        let listener = UnixListener::bind(&self.path).map_err(bind_error)?;
+        listener.set_nonblocking(true)?;
+        if let Some(mode) = self.mode {
+            fs::set_permissions(&self.path, Permissions::from_mode(mode)).map_err(bind_error)?;
+        }
+        Ok(listener)
+    }
+}
+
+/// The process on the other end of a Unix socket connection, as vouched for by the kernel.
+/// Useful to authorize local clients without passwords or tokens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Not available on every platform.
+    pub pid: Option<i32>,
+}
+
+pub(crate) fn peer_credentials(stream: &tokio::net::UnixStream) -> Option<PeerCredentials> {
+    let credentials = stream.peer_cred().ok()?;
+    Some(PeerCredentials {
+        uid: credentials.uid(),
+        gid: credentials.gid(),
+        pid: credentials.pid(),
+    })
+}
+
+fn remove_stale(path: &Path) -> io::Result<()> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        // Binding fails with "address in use", better than deleting someone's file.
+        return Ok(());
+    }
+    match UnixStream::connect(path) {
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        // Either another server is running, which binding will report, or we can't tell.
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,231 @@
+use crate::utils::helpers::format_http_date;
+use crate::utils::request::Request;
+use crate::utils::response::Response;
+use crate::utils::static_files::Dotfiles;
+use crate::utils::status::StatusCode;
+use serde_json::json;
+use std::cmp::Ordering;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+/// Lists the directory at `dir`, as JSON when the client asks for it and as an HTML page otherwise.
+/// Sorted by `?sort=name|size|modified` and `?order=asc|desc`, directories always first.
+/// Entries resolving outside `root` (symlinks) are left out, and so are dotfiles unless allowed.
+/// Both paths must already be canonical.
+pub(crate) async fn listing(
+    request: &Request,
+    root: &Path,
+    dir: &Path,
+    dotfiles: Dotfiles,
+) -> Response {
+    let mut entries = match read_entries(root, dir, dotfiles).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("Error while listing directory! {}", err);
+            return Response::new().error(
+                "Error while listing directory",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            );
+        }
+    };
+
+    let key = match request.query.get("sort").map(|sort| sort.as_str()) {
+        Some("size") => SortKey::Size,
+        Some("modified") | Some("mtime") => SortKey::Modified,
+        _ => SortKey::Name,
+    };
+    let descending = request
+        .query
+        .get("order")
+        .is_some_and(|order| order == "desc");
+    entries.sort_by(|a, b| {
+        let order = match key {
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+            SortKey::Modified => a
+                .modified
+                .cmp(&b.modified)
+                .then_with(|| a.name.cmp(&b.name)),
+        };
+        let order = if descending { order.reverse() } else { order };
+        match (a.is_dir, b.is_dir) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => order,
+        }
+    });
+
+    let path = urlencoding::decode(&request.route)
+        .map(|path| path.into_owned())
+        .unwrap_or_else(|_| request.route.clone());
+    let wants_json = request
+        .header("accept")
+        .is_some_and(|accept| accept.contains("application/json"));
+    let mut response = if wants_json {
+        json_listing(&path, &entries)
+    } else {
+        html_listing(&path, &entries, dir != root, key, descending)
+    };
+    response
+        .headers
+        .insert("Vary".to_owned(), "Accept".to_owned());
+    response
+}
+
+async fn read_entries(root: &Path, dir: &Path, dotfiles: Dotfiles) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && dotfiles != Dotfiles::Allow {
+            continue;
+        }
+        // Links out of the root would only 404 when followed, so don't offer them.
+        let Ok(target) = tokio::fs::canonicalize(entry.path()).await else {
+            continue;
+        };
+        if !target.starts_with(root) {
+            continue;
+        }
+        let Ok(metadata) = tokio::fs::metadata(&target).await else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+fn json_listing(path: &str, entries: &[Entry]) -> Response {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "name": entry.name,
+                "type": if entry.is_dir { "directory" } else { "file" },
+                "size": entry.size,
+                "modified": entry
+                    .modified
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|modified| modified.as_secs()),
+                "url": href(entry),
+            })
+        })
+        .collect();
+    Response::new().json(json!({ "path": path, "entries": entries }), StatusCode::OK)
+}
+
+fn html_listing(
+    path: &str,
+    entries: &[Entry],
+    has_parent: bool,
+    key: SortKey,
+    descending: bool,
+) -> Response {
+    let title = format!("Index of {}", escape_html(path));
+    // Clicking the current sort column flips the order, any other column sorts ascending.
+    let column = |label: &str, column: SortKey, query: &str| {
+        let order = if column == key && !descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        format!(
+            "<th><a href=\"?sort={}&amp;order={}\">{}</a></th>",
+            query, order, label
+        )
+    };
+
+    let mut rows = String::new();
+    if has_parent {
+        rows += "<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n";
+    }
+    for entry in entries {
+        let name = if entry.is_dir {
+            format!("{}/", entry.name)
+        } else {
+            entry.name.clone()
+        };
+        rows += &format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            href(entry),
+            escape_html(&name),
+            if entry.is_dir {
+                "-".to_owned()
+            } else {
+                format_size(entry.size)
+            },
+            entry.modified.map(format_http_date).unwrap_or_default()
+        );
+    }
+
+    let html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n</head>\n\
+         <body>\n<h1>{title}</h1>\n<table>\n<thead><tr>{}{}{}</tr></thead>\n<tbody>\n{rows}</tbody>\n\
+         </table>\n</body>\n</html>\n",
+        column("Name", SortKey::Name, "name"),
+        column("Size", SortKey::Size, "size"),
+        column("Modified", SortKey::Modified, "modified"),
+    );
+    let mut response = Response::new().text(&html, StatusCode::OK);
+    response.content_type = "text/html; charset=utf-8".to_owned();
+    response
+}
+
+/// A link relative to the directory, so it works under any mount prefix.
+fn href(entry: &Entry) -> String {
+    let name = urlencoding::encode(&entry.name);
+    if entry.is_dir {
+        format!("{}/", name)
+    } else {
+        name.into_owned()
+    }
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", size)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,236 @@
+use crate::utils::helpers::parse_http_date;
+use crate::utils::request::Request;
+use crate::utils::response::Response;
+use crate::utils::status::StatusCode;
+use base64::prelude::*;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// An entity tag identifying one version of a representation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ETag {
+    tag: String,
+    weak: bool,
+}
+
+impl ETag {
+    /// A strong tag promises the bytes are identical whenever the tag is.
+    pub fn strong(tag: &str) -> Self {
+        Self {
+            tag: tag.replace('"', ""),
+            weak: false,
+        }
+    }
+
+    /// A weak tag only promises the representations are equivalent.
+    pub fn weak(tag: &str) -> Self {
+        Self {
+            tag: tag.replace('"', ""),
+            weak: true,
+        }
+    }
+
+    /// A strong tag derived from a SHA-256 hash of the content.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let digest = Sha256::digest(bytes);
+        Self::strong(&BASE64_URL_SAFE_NO_PAD.encode(&digest[..16]))
+    }
+
+    /// The opaque tag without quotes or weakness prefix.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    /// Parses a single header value such as `"abc"` or `W/"abc"`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (weak, quoted) = match value.strip_prefix("W/") {
+            Some(rest) => (true, rest),
+            None => (false, value),
+        };
+        let tag = quoted.strip_prefix('"')?.strip_suffix('"')?;
+        if tag.contains('"') {
+            return None;
+        }
+        Some(Self {
+            tag: tag.to_owned(),
+            weak,
+        })
+    }
+
+    /// Both tags are strong and identical. Used for `If-Match` and `If-Range`.
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// The tags are identical, ignoring weakness. Used for `If-None-Match`.
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            write!(f, "W/\"{}\"", self.tag)
+        } else {
+            write!(f, "\"{}\"", self.tag)
+        }
+    }
+}
+
+/// `*` or a comma separated list of entity tags, as sent in `If-Match` and `If-None-Match`.
+enum TagList {
+    Any,
+    Tags(Vec<ETag>),
+}
+
+impl TagList {
+    fn parse(header: &str) -> Self {
+        if header.trim() == "*" {
+            return TagList::Any;
+        }
+        // Tags may contain commas, so split on the closing quotes instead.
+        let mut tags = Vec::new();
+        let mut rest = header;
+        while let Some(start) = rest.find('"') {
+            let prefix = rest[..start].trim_start_matches([',', ' ', '\t']);
+            let Some(len) = rest[start + 1..].find('"') else {
+                break;
+            };
+            let end = start + 1 + len;
+            let weak = prefix.ends_with("W/");
+            let tag = &rest[start + 1..end];
+            tags.push(if weak {
+                ETag::weak(tag)
+            } else {
+                ETag::strong(tag)
+            });
+            rest = &rest[end + 1..];
+        }
+        TagList::Tags(tags)
+    }
+
+    fn matches(
+        &self,
+        exists: bool,
+        etag: Option<&ETag>,
+        compare: fn(&ETag, &ETag) -> bool,
+    ) -> bool {
+        match (self, etag) {
+            (TagList::Any, _) => exists,
+            (TagList::Tags(tags), Some(etag)) => tags.iter().any(|tag| compare(tag, etag)),
+            (TagList::Tags(_), None) => false,
+        }
+    }
+}
+
+/// Evaluates the request's preconditions against the current validators of a resource,
+/// following the order in RFC 9110 section 13.2.2. Returns `304 Not Modified` or
+/// `412 Precondition Failed` when the request should not be carried out.
+///
+/// Conditional GETs are answered automatically for responses carrying an `ETag` or
+/// `Last-Modified`. Handlers for unsafe methods should call this themselves before
+/// changing anything, so an `If-Match` from a stale client cannot overwrite newer data.
+/// Pass `None` for both validators when the resource does not exist yet.
+pub fn evaluate(
+    request: &Request,
+    etag: Option<&ETag>,
+    last_modified: Option<SystemTime>,
+) -> Option<StatusCode> {
+    let exists = etag.is_some() || last_modified.is_some();
+    let last_modified = last_modified.map(truncate_to_seconds);
+    let is_read = request.is_read();
+
+    if let Some(if_match) = request.header("if-match") {
+        if !TagList::parse(if_match).matches(exists, etag, ETag::strong_eq) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    } else if let Some(since) = request
+        .header("if-unmodified-since")
+        .and_then(parse_http_date)
+    {
+        if last_modified.is_some_and(|modified| modified > since) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    if let Some(if_none_match) = request.header("if-none-match") {
+        if TagList::parse(if_none_match).matches(exists, etag, ETag::weak_eq) {
+            return Some(if is_read {
+                StatusCode::NOT_MODIFIED
+            } else {
+                StatusCode::PRECONDITION_FAILED
+            });
+        }
+    } else if is_read {
+        if let Some(since) = request
+            .header("if-modified-since")
+            .and_then(parse_http_date)
+        {
+            if last_modified.is_some_and(|modified| modified <= since) {
+                return Some(StatusCode::NOT_MODIFIED);
+            }
+        }
+    }
+    None
+}
+
+/// Answers conditional GETs and HEADs for successful responses that carry validators.
+pub(crate) fn apply(request: &Request, response: Response) -> Response {
+    if !request.is_read() || !response.status.is_success() {
+        return response;
+    }
+
+    let etag = response.header("etag").and_then(ETag::parse);
+    let last_modified = response.header("last-modified").and_then(parse_http_date);
+    if etag.is_none() && last_modified.is_none() {
+        return response;
+    }
+
+    match evaluate(request, etag.as_ref(), last_modified) {
+        Some(StatusCode::NOT_MODIFIED) => not_modified(response),
+        Some(status) => {
+            let mut failed = Response::new().error("Precondition Failed", status);
+            failed.cookies = response.cookies;
+            failed
+        }
+        None => response,
+    }
+}
+
+/// A bodiless 304 keeping the headers a cache needs to update its stored response.
+fn not_modified(response: Response) -> Response {
+    let mut not_modified = Response::new().text("", StatusCode::NOT_MODIFIED);
+    not_modified.cookies = response.cookies;
+    for (name, value) in response.headers {
+        let keep = [
+            "etag",
+            "last-modified",
+            "cache-control",
+            "vary",
+            "expires",
+            "content-location",
+        ]
+        .iter()
+        .any(|keep| name.eq_ignore_ascii_case(keep));
+        if keep {
+            not_modified.headers.insert(name, value);
+        }
+    }
+    not_modified
+}
+
+// HTTP dates only have second precision, so compare file times the same way.
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
@@ -0,0 +1,663 @@
+use crate::tls::ClientCertificate;
+#[cfg(unix)]
+use crate::unix::PeerCredentials;
+#[cfg(feature = "http2")]
+use crate::utils::http2;
+use crate::utils::request::{parse_head, Request};
+use crate::utils::status::StatusCode;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+// Clients get this long before the minimum body rate starts being enforced.
+const MIN_RATE_GRACE: Duration = Duration::from_secs(5);
+
+/// What an HTTP/2 client sends first when it doesn't negotiate the protocol through TLS.
+#[cfg(feature = "http2")]
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The preface is followed by a SETTINGS frame, with its 9 byte header saying how long it is.
+#[cfg(feature = "http2")]
+const FRAME_HEADER_LENGTH: usize = 9;
+#[cfg(feature = "http2")]
+const SETTINGS_FRAME: u8 = 0x4;
+
+/// Limits applied to every connection an `App` accepts.
+#[derive(Clone, Debug)]
+pub(crate) struct Limits {
+    /// How long a kept-alive connection may sit idle waiting for the next request.
+    pub(crate) keep_alive: Duration,
+    /// Requests served on one connection before it is closed.
+    pub(crate) max_requests: usize,
+    /// Size of the request line plus headers.
+    pub(crate) max_head: usize,
+    pub(crate) max_body: usize,
+    /// Time from the first byte of a request to the end of its headers.
+    pub(crate) header_timeout: Duration,
+    /// Time to receive the whole body.
+    pub(crate) body_timeout: Duration,
+    /// Average bytes per second a body has to arrive at, `0` to disable.
+    pub(crate) min_body_rate: u64,
+    pub(crate) handler_timeout: Option<Duration>,
+    /// Time a single write may wait for the client to read.
+    pub(crate) write_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            keep_alive: Duration::from_secs(5),
+            max_requests: 1000,
+            max_head: 16 * 1024,
+            max_body: 1024 * 1024,
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(60),
+            min_body_rate: 240,
+            handler_timeout: None,
+            write_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+pub(crate) enum ReadError {
+    /// The connection broke, there is nobody to answer.
+    Io(io::Error),
+    /// The request can't be served, answer with this status and close.
+    Status(StatusCode),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::TimedOut {
+            return ReadError::Status(StatusCode::REQUEST_TIMEOUT);
+        }
+        ReadError::Io(err)
+    }
+}
+
+/// A deadline for reading part of a request, pushed back as data arrives when a minimum
+/// rate is set. Stalling clients (slowloris) run into it instead of holding a task forever.
+struct ReadTimer {
+    started: Instant,
+    received: u64,
+    timeout: Duration,
+    min_rate: u64,
+}
+
+impl ReadTimer {
+    fn new(timeout: Duration, min_rate: u64) -> Self {
+        Self {
+            started: Instant::now(),
+            received: 0,
+            timeout,
+            min_rate,
+        }
+    }
+
+    fn deadline(&self) -> Instant {
+        let deadline = self.started + self.timeout;
+        if self.min_rate == 0 {
+            return deadline;
+        }
+        let earned = Duration::from_secs_f64(self.received as f64 / self.min_rate as f64);
+        deadline.min(self.started + MIN_RATE_GRACE + earned)
+    }
+}
+
+/// The socket a connection is served over.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+    Tls(Box<tokio_rustls::server::TlsStream<Stream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// What is known about the other end of a connection, copied onto every request it sends.
+#[derive(Clone, Default)]
+pub(crate) struct Peer {
+    listener: Arc<str>,
+    client_certificate: Option<ClientCertificate>,
+    #[cfg(unix)]
+    credentials: Option<PeerCredentials>,
+}
+
+impl Peer {
+    fn of(stream: &Stream) -> Self {
+        match stream {
+            Stream::Tcp(_) => Self::default(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Self {
+                credentials: crate::unix::peer_credentials(stream),
+                ..Self::default()
+            },
+            Stream::Tls(stream) => {
+                let (inner, session) = stream.get_ref();
+                Self {
+                    client_certificate: crate::tls::client_certificate(session),
+                    ..Self::of(inner)
+                }
+            }
+        }
+    }
+
+    pub(crate) fn apply(&self, request: &mut Request) {
+        request.listener = self.listener.to_string();
+        request.client_certificate = self.client_certificate.clone();
+        #[cfg(unix)]
+        {
+            request.peer_credentials = self.credentials;
+        }
+    }
+}
+
+/// A client connection. Bytes read past the end of one request stay buffered for the next,
+/// which is what makes pipelining work.
+pub(crate) struct Connection {
+    stream: Stream,
+    buffer: Vec<u8>,
+    timer: Option<ReadTimer>,
+    peer: Peer,
+}
+
+impl Connection {
+    pub(crate) fn new(stream: Stream, listener: Arc<str>) -> Self {
+        Self {
+            peer: Peer {
+                listener,
+                ..Peer::of(&stream)
+            },
+            stream,
+            buffer: Vec::new(),
+            timer: None,
+        }
+    }
+
+    pub(crate) fn stream(&mut self) -> &mut Stream {
+        &mut self.stream
+    }
+
+    /// The socket, whatever was read from it but not consumed yet, and who is on the other end.
+    #[cfg(feature = "http2")]
+    pub(crate) fn into_parts(self) -> (Stream, Vec<u8>, Peer) {
+        (self.stream, self.buffer, self.peer)
+    }
+
+    /// Whether the client speaks HTTP/2, either agreed on through ALPN or, over plain TCP,
+    /// by opening with the connection preface (prior knowledge).
+    #[cfg(feature = "http2")]
+    pub(crate) async fn is_http2(&mut self, limits: &Limits) -> bool {
+        if let Stream::Tls(ref stream) = self.stream {
+            return stream.get_ref().1.alpn_protocol() == Some(b"h2");
+        }
+        self.timer = Some(ReadTimer::new(limits.header_timeout, 0));
+        loop {
+            let length = self.buffer.len().min(PREFACE.len());
+            if self.buffer[..length] != PREFACE[..length] {
+                return false;
+            }
+            if length == PREFACE.len() {
+                return true;
+            }
+            // Errors and timeouts come up again when the request is read.
+            if !matches!(self.fill().await, Ok(n) if n > 0) {
+                return false;
+            }
+        }
+    }
+
+    /// Switches to HTTP/2 when the request asks for it with `Upgrade: h2c` (RFC 7540,
+    /// section 3.2). Once the client has sent its preface, the request is slipped into the
+    /// connection as stream 1, which is where the answer goes. `Ok(false)` when the request
+    /// doesn't ask for the upgrade or can't have it, an error when the client doesn't follow
+    /// through after `101 Switching Protocols`.
+    #[cfg(feature = "http2")]
+    pub(crate) async fn upgrade_h2c(
+        &mut self,
+        request: &Request,
+        limits: &Limits,
+    ) -> io::Result<bool> {
+        // Over TLS, HTTP/2 is only agreed on through ALPN.
+        if matches!(self.stream, Stream::Tls(_)) {
+            return Ok(false);
+        }
+        let Some(headers) = http2::upgrade_headers(request) else {
+            return Ok(false);
+        };
+        with_timeout(limits.write_timeout, async {
+            self.stream
+                .write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n",
+                )
+                .await?;
+            self.stream.flush().await
+        })
+        .await?;
+
+        self.timer = Some(ReadTimer::new(limits.header_timeout, 0));
+        let settings_end = loop {
+            let length = self.buffer.len().min(PREFACE.len());
+            if self.buffer[..length] != PREFACE[..length] {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Client did not send the HTTP/2 preface after upgrading",
+                ));
+            }
+            if let Some(frame) = self
+                .buffer
+                .get(PREFACE.len()..PREFACE.len() + FRAME_HEADER_LENGTH)
+            {
+                if frame[3] != SETTINGS_FRAME {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "HTTP/2 preface is not followed by SETTINGS",
+                    ));
+                }
+                let length = u32::from_be_bytes([0, frame[0], frame[1], frame[2]]) as usize;
+                let end = PREFACE.len() + FRAME_HEADER_LENGTH + length;
+                if self.buffer.len() >= end {
+                    break end;
+                }
+            }
+            if self.fill().await? == 0 {
+                return Err(unexpected_eof());
+            }
+        };
+        self.timer = None;
+        self.buffer.splice(settings_end..settings_end, headers);
+        Ok(true)
+    }
+
+    /// Waits until the client starts sending another request. Returns `false` when the client
+    /// closes the connection or stays silent for longer than `idle`.
+    pub(crate) async fn wait_for_request(&mut self, idle: Duration) -> bool {
+        self.timer = None;
+        if !self.buffer.is_empty() {
+            return true;
+        }
+        matches!(tokio::time::timeout(idle, self.fill()).await, Ok(Ok(n)) if n > 0)
+    }
+
+    /// Reads the next request, body included. `Ok(None)` means the client closed the connection
+    /// before sending anything.
+    pub(crate) async fn read_request(
+        &mut self,
+        limits: &Limits,
+    ) -> Result<Option<Request>, ReadError> {
+        self.timer = Some(ReadTimer::new(limits.header_timeout, 0));
+        let Some(head) = self.read_head(limits.max_head).await? else {
+            return Ok(None);
+        };
+        let mut request = parse_head(&String::from_utf8_lossy(&head)).map_err(ReadError::Status)?;
+
+        let chunked = request.is_chunked();
+        if !chunked && request.content_length > limits.max_body {
+            return Err(ReadError::Status(StatusCode::CONTENT_TOO_LARGE));
+        }
+
+        let expects_body = chunked || request.content_length > 0;
+        let wants_continue = request
+            .header("expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
+        if expects_body && wants_continue && self.buffer.is_empty() {
+            with_timeout(limits.write_timeout, async {
+                self.stream
+                    .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                    .await?;
+                self.stream.flush().await
+            })
+            .await?;
+        }
+
+        self.timer = Some(ReadTimer::new(limits.body_timeout, limits.min_body_rate));
+
+        let body = if chunked {
+            self.read_chunked(limits).await?
+        } else {
+            self.take(request.content_length).await?
+        };
+        self.timer = None;
+        request.set_body(&body);
+        self.peer.apply(&mut request);
+        Ok(Some(request))
+    }
+
+    async fn read_head(&mut self, max_head: usize) -> Result<Option<Vec<u8>>, ReadError> {
+        // Clients may send stray line breaks between pipelined requests.
+        loop {
+            while self.buffer.starts_with(b"\r\n") {
+                self.buffer.drain(..2);
+            }
+            if let Some(end) = find(&self.buffer, b"\r\n\r\n") {
+                let head = self.buffer[..end].to_vec();
+                self.buffer.drain(..end + 4);
+                return Ok(Some(head));
+            }
+            if self.buffer.len() > max_head {
+                return Err(ReadError::Status(
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                ));
+            }
+            if self.fill().await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(unexpected_eof().into());
+            }
+        }
+    }
+
+    /// Decodes a `Transfer-Encoding: chunked` body, discarding any trailers.
+    async fn read_chunked(&mut self, limits: &Limits) -> Result<Vec<u8>, ReadError> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            // No whitespace around the size either, other parsers may read ` 5` differently.
+            let size = line.split(';').next().unwrap_or("");
+            if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                return Err(ReadError::Status(StatusCode::BAD_REQUEST));
+            }
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| ReadError::Status(StatusCode::CONTENT_TOO_LARGE))?;
+            if size == 0 {
+                // Trailers are headers too, and are held to the same limit.
+                let mut trailers = 0;
+                loop {
+                    let line = self.read_line().await?;
+                    if line.is_empty() {
+                        return Ok(body);
+                    }
+                    trailers += line.len() + 2;
+                    if trailers > limits.max_head {
+                        return Err(ReadError::Status(
+                            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                        ));
+                    }
+                }
+            }
+            if size > limits.max_body.saturating_sub(body.len()) {
+                return Err(ReadError::Status(StatusCode::CONTENT_TOO_LARGE));
+            }
+            body.extend(self.take(size).await?);
+            if !self.read_line().await?.is_empty() {
+                return Err(ReadError::Status(StatusCode::BAD_REQUEST));
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String, ReadError> {
+        loop {
+            if let Some(end) = find(&self.buffer, b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+                self.buffer.drain(..end + 2);
+                return Ok(line);
+            }
+            // Chunk size lines and trailers are short, anything else is garbage.
+            if self.buffer.len() > 4096 {
+                return Err(ReadError::Status(StatusCode::BAD_REQUEST));
+            }
+            if self.fill().await? == 0 {
+                return Err(unexpected_eof().into());
+            }
+        }
+    }
+
+    async fn take(&mut self, length: usize) -> io::Result<Vec<u8>> {
+        while self.buffer.len() < length {
+            if self.fill().await? == 0 {
+                return Err(unexpected_eof());
+            }
+        }
+        Ok(self.buffer.drain(..length).collect())
+    }
+
+    async fn fill(&mut self) -> io::Result<usize> {
+        self.buffer.reserve(8 * 1024);
+        let Some(ref mut timer) = self.timer else {
+            return self.stream.read_buf(&mut self.buffer).await;
+        };
+        let n = tokio::time::timeout_at(timer.deadline(), self.stream.read_buf(&mut self.buffer))
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Client was too slow sending the request",
+                )
+            })??;
+        timer.received += n as u64;
+        Ok(n)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Runs a write, failing with `TimedOut` if the client doesn't take the data in time.
+pub(crate) async fn with_timeout<T>(
+    limit: Duration,
+    write: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    tokio::time::timeout(limit, write)
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Client stopped reading the response",
+            ))
+        })
+}
+
+/// Errors that only mean the client went away or stopped responding, not worth reporting.
+pub(crate) fn is_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+    )
+}
+
+fn unexpected_eof() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Connection closed in the middle of a request",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads one request from a connection the client has sent `raw` on and closed.
+    async fn read(raw: &[u8], limits: &Limits) -> Result<Option<Request>, ReadError> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client.write_all(raw).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut connection = Connection::new(Stream::Tcp(server), Arc::from("test"));
+        connection.read_request(limits).await
+    }
+
+    async fn status(raw: &[u8]) -> Option<StatusCode> {
+        match read(raw, &Limits::default()).await {
+            Err(ReadError::Status(status)) => Some(status),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_chunked_bodies() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n";
+        let request = read(raw, &Limits::default()).await.ok().flatten().unwrap();
+        assert_eq!(
+            request.raw,
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nhello world"
+        );
+        assert_eq!(request.listener, "test");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_and_malformed_chunks() {
+        let chunked = |chunks: &str| {
+            format!(
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+                chunks
+            )
+        };
+        // Used to overflow `body.len() + size`.
+        let overflow = chunked("1\r\na\r\nffffffffffffffff\r\n");
+        assert_eq!(
+            status(overflow.as_bytes()).await,
+            Some(StatusCode::CONTENT_TOO_LARGE)
+        );
+        let too_long = chunked("1ffffffffffffffff\r\n");
+        assert_eq!(
+            status(too_long.as_bytes()).await,
+            Some(StatusCode::CONTENT_TOO_LARGE)
+        );
+        let too_big = chunked("100001\r\n");
+        assert_eq!(
+            status(too_big.as_bytes()).await,
+            Some(StatusCode::CONTENT_TOO_LARGE)
+        );
+        for size in ["+5", "-5", "", "zz", " ", " 5", "5 ", "5\t;a=b"] {
+            let raw = chunked(&format!("{}\r\nhello\r\n0\r\n\r\n", size));
+            assert_eq!(
+                status(raw.as_bytes()).await,
+                Some(StatusCode::BAD_REQUEST),
+                "{:?}",
+                size
+            );
+        }
+        let missing_crlf = chunked("5\r\nhelloX\r\n0\r\n\r\n");
+        assert_eq!(
+            status(missing_crlf.as_bytes()).await,
+            Some(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_trailers() {
+        let mut raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n".to_vec();
+        for _ in 0..1000 {
+            raw.extend_from_slice(b"Trailer: xxxxxxxxxxxxxxxxxxxxxxxx\r\n");
+        }
+        raw.extend_from_slice(b"\r\n");
+        assert_eq!(
+            status(&raw).await,
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_ambiguous_framing() {
+        let conflicting = b"POST / HTTP/1.1\r\nContent-Length: 0\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(status(conflicting).await, Some(StatusCode::BAD_REQUEST));
+        let both = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(status(both).await, Some(StatusCode::BAD_REQUEST));
+        let gzip = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(status(gzip).await, Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[tokio::test]
+    async fn enforces_body_limit_from_content_length() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 2000000\r\n\r\n";
+        assert_eq!(status(raw).await, Some(StatusCode::CONTENT_TOO_LARGE));
+    }
+
+    #[tokio::test]
+    async fn reads_pipelined_requests_in_order() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client
+            .write_all(
+                b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc\r\nGET /b HTTP/1.1\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut connection = Connection::new(Stream::Tcp(server), Arc::from("test"));
+        let limits = Limits::default();
+        let first = connection
+            .read_request(&limits)
+            .await
+            .ok()
+            .flatten()
+            .unwrap();
+        assert_eq!(first.route, "/a");
+        assert!(first.raw.ends_with("abc"));
+        let second = connection
+            .read_request(&limits)
+            .await
+            .ok()
+            .flatten()
+            .unwrap();
+        assert_eq!(second.route, "/b");
+        client.shutdown().await.unwrap();
+        assert!(matches!(connection.read_request(&limits).await, Ok(None)));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::utils::helpers::format_http_date;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A `Set-Cookie` value. New cookies default to `Path=/; HttpOnly` and live for the
+/// browser session unless `max_age` or `expires` is set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    pub expires: Option<SystemTime>,
+    pub max_age: Option<Duration>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+    pub partitioned: bool,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            domain: None,
+            path: Some("/".to_owned()),
+            expires: None,
+            max_age: None,
+            secure: false,
+            http_only: true,
+            same_site: None,
+            partitioned: false,
+        }
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_owned());
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    pub fn expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Browsers drop `SameSite=None` cookies that aren't `Secure`, so `None` also turns on
+    /// `Secure`.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        if same_site == SameSite::None {
+            self.secure = true;
+        }
+        self
+    }
+
+    /// Partitioned (CHIPS) cookies are only accepted over secure connections,
+    /// so this also turns on `Secure`.
+    pub fn partitioned(mut self, partitioned: bool) -> Self {
+        self.partitioned = partitioned;
+        if partitioned {
+            self.secure = true;
+        }
+        self
+    }
+
+    /// Turns the cookie into one that makes the browser delete it immediately.
+    pub fn expire(mut self) -> Self {
+        self.value = String::new();
+        self.max_age = Some(Duration::ZERO);
+        self.expires = Some(UNIX_EPOCH);
+        self
+    }
+
+    pub fn to_header_value(&self) -> String {
+        let mut header = format!(
+            "{}={}",
+            sanitize_name(&self.name),
+            urlencoding::encode(&self.value)
+        );
+        if let Some(ref domain) = self.domain {
+            header += &format!("; Domain={}", sanitize_attribute(domain));
+        }
+        if let Some(ref path) = self.path {
+            header += &format!("; Path={}", sanitize_attribute(path));
+        }
+        if let Some(expires) = self.expires {
+            header += &format!("; Expires={}", format_http_date(expires));
+        }
+        if let Some(max_age) = self.max_age {
+            header += &format!("; Max-Age={}", max_age.as_secs());
+        }
+        if self.secure {
+            header += "; Secure";
+        }
+        if self.http_only {
+            header += "; HttpOnly";
+        }
+        if let Some(same_site) = self.same_site {
+            header += match same_site {
+                SameSite::Strict => "; SameSite=Strict",
+                SameSite::Lax => "; SameSite=Lax",
+                SameSite::None => "; SameSite=None",
+            };
+        }
+        if self.partitioned {
+            header += "; Partitioned";
+        }
+        header
+    }
+}
+
+// Names must be an RFC 6265 token, anything else could smuggle in a value or attribute.
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .filter(|&c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c))
+        .collect()
+}
+
+// Attribute values are user controlled, so drop anything that could end the attribute or the header.
+fn sanitize_attribute(value: &str) -> String {
+    value.replace([';', '\r', '\n'], "")
+}
+
+/// Parses a `Cookie` request header (`a=1; b=2`) into name/value pairs.
+pub fn parse_cookie_header(header: &str) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for pair in header.split(';') {
+        let mut parts = pair.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        if name.is_empty() {
+            continue;
+        }
+        if let Some(value) = parts.next() {
+            let value = value.trim().trim_matches('"');
+            let value = urlencoding::decode(value).unwrap_or_else(|_| value.into());
+            // The first occurrence wins, it is the one with the most specific path.
+            cookies
+                .entry(name.to_owned())
+                .or_insert_with(|| value.to_string());
+        }
+    }
+    cookies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_name_to_a_token() {
+        let cookie = Cookie::new("id=1; Domain=evil.example\r\nX: y", "v");
+        assert_eq!(
+            cookie.to_header_value(),
+            "id1Domainevil.exampleXy=v; Path=/; HttpOnly"
+        );
+        assert_eq!(
+            Cookie::new("a b\t\"(c)\u{e9}", "").to_header_value(),
+            "abc=; Path=/; HttpOnly"
+        );
+        assert_eq!(
+            Cookie::new("__Host-id_1.x!", "1").to_header_value(),
+            "__Host-id_1.x!=1; Path=/; HttpOnly"
+        );
+    }
+
+    #[test]
+    fn encodes_value_and_sanitizes_attributes() {
+        let cookie = Cookie::new("id", "a b;c")
+            .path("/x; Secure\r\n")
+            .same_site(SameSite::Lax)
+            .max_age(Duration::from_secs(60));
+        assert_eq!(
+            cookie.to_header_value(),
+            "id=a%20b%3Bc; Path=/x Secure; Max-Age=60; HttpOnly; SameSite=Lax"
+        );
+    }
+
+    #[test]
+    fn cross_site_cookies_are_secure() {
+        assert!(Cookie::new("id", "1").same_site(SameSite::None).secure);
+        assert!(Cookie::new("id", "1").partitioned(true).secure);
+        assert!(!Cookie::new("id", "1").same_site(SameSite::Lax).secure);
+        assert_eq!(
+            Cookie::new("id", "1")
+                .same_site(SameSite::None)
+                .to_header_value(),
+            "id=1; Path=/; Secure; HttpOnly; SameSite=None"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use crate::error::{Error, Result};
+use crate::utils::cookie::Cookie;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 12;
+
+/// Signing and encryption keys derived from one server secret.
+#[derive(Clone)]
+struct Key {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl Key {
+    fn derive(secret: &[u8]) -> Result<Self> {
+        if secret.len() < 32 {
+            return Err(Error::Config {
+                key: "cookie secret".to_owned(),
+                message: format!("must be at least 32 bytes long, not {}", secret.len()),
+            });
+        }
+        Ok(Self {
+            signing: derive_subkey(secret, b"rust-express cookie signing"),
+            encryption: derive_subkey(secret, b"rust-express cookie encryption"),
+        })
+    }
+
+    fn mac(&self, name: &str, value: &str) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.signing)
+            .expect("HMAC accepts keys of any length");
+        // Binding the name stops a signed value from being replayed under another cookie.
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+}
+
+fn derive_subkey(secret: &[u8], label: &[u8]) -> [u8; 32] {
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+/// Signs and encrypts cookie values with a server secret.
+///
+/// The first secret is used for everything that gets written, while every configured
+/// secret is accepted when reading, so secrets can be rotated without logging users out.
+/// Secrets shorter than 32 bytes are refused with `Error::Config`.
+#[derive(Clone)]
+pub struct CookieJar {
+    keys: Vec<Key>,
+}
+
+impl CookieJar {
+    pub fn new(secret: &[u8]) -> Result<Self> {
+        Ok(Self {
+            keys: vec![Key::derive(secret)?],
+        })
+    }
+
+    /// Accepts cookies written with an older secret.
+    pub fn fallback(mut self, secret: &[u8]) -> Result<Self> {
+        self.keys.push(Key::derive(secret)?);
+        Ok(self)
+    }
+
+    /// Replaces the signing secret, keeping the previous one as a fallback.
+    pub fn rotate(&mut self, secret: &[u8]) -> Result<()> {
+        self.keys.insert(0, Key::derive(secret)?);
+        Ok(())
+    }
+
+    /// Appends an HMAC-SHA256 tag to the cookie value. The value stays readable by the client.
+    pub fn sign(&self, mut cookie: Cookie) -> Cookie {
+        let tag = self.keys[0].mac(&cookie.name, &cookie.value).finalize();
+        cookie.value = format!(
+            "{}.{}",
+            cookie.value,
+            BASE64_URL_SAFE_NO_PAD.encode(tag.into_bytes())
+        );
+        cookie
+    }
+
+    /// Returns the original value if the tag matches any configured secret.
+    pub fn verify(&self, name: &str, signed_value: &str) -> Option<String> {
+        let (value, tag) = signed_value.rsplit_once('.')?;
+        let tag = BASE64_URL_SAFE_NO_PAD.decode(tag).ok()?;
+        self.keys
+            .iter()
+            .any(|key| key.mac(name, value).verify_slice(&tag).is_ok())
+            .then(|| value.to_owned())
+    }
+
+    /// Encrypts the cookie value with AES-256-GCM so the client can neither read nor modify it.
+    pub fn encrypt(&self, mut cookie: Cookie) -> Cookie {
+        let cipher = Aes256Gcm::new(&self.keys[0].encryption.into());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: cookie.value.as_bytes(),
+            aad: cookie.name.as_bytes(),
+        };
+        let sealed = cipher
+            .encrypt(&nonce, payload)
+            .expect("AES-GCM encryption of a cookie cannot fail");
+
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&sealed);
+        cookie.value = BASE64_URL_SAFE_NO_PAD.encode(data);
+        cookie
+    }
+
+    /// Returns the plaintext if the value decrypts and authenticates under any configured secret.
+    pub fn decrypt(&self, name: &str, encrypted_value: &str) -> Option<String> {
+        let data = BASE64_URL_SAFE_NO_PAD.decode(encrypted_value).ok()?;
+        if data.len() <= NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+
+        self.keys.iter().find_map(|key| {
+            let cipher = Aes256Gcm::new(&key.encryption.into());
+            let payload = Payload {
+                msg: sealed,
+                aad: name.as_bytes(),
+            };
+            let plain = cipher.decrypt(Nonce::from_slice(nonce), payload).ok()?;
+            String::from_utf8(plain).ok()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"a-secret-that-is-at-least-32-bytes";
+    const OLD_SECRET: &[u8] = b"an-older-secret-of-at-least-32-bytes";
+
+    #[test]
+    fn refuses_short_secrets() {
+        assert!(matches!(
+            CookieJar::new(b"too short"),
+            Err(Error::Config { .. })
+        ));
+        let jar = CookieJar::new(SECRET).unwrap();
+        assert!(jar.clone().fallback(&SECRET[..31]).is_err());
+        let mut rotated = jar;
+        assert!(rotated.rotate(b"").is_err());
+        assert_eq!(rotated.keys.len(), 1);
+    }
+
+    #[test]
+    fn verifies_signed_values() {
+        let jar = CookieJar::new(SECRET).unwrap();
+        let signed = jar.sign(Cookie::new("user", "42")).value;
+        assert!(signed.starts_with("42."));
+        assert_eq!(jar.verify("user", &signed), Some("42".to_owned()));
+
+        let tag = signed.rsplit_once('.').unwrap().1;
+        assert_eq!(jar.verify("user", &format!("43.{}", tag)), None);
+        assert_eq!(jar.verify("admin", &signed), None);
+        assert_eq!(jar.verify("user", "42"), None);
+        let other = CookieJar::new(OLD_SECRET).unwrap();
+        assert_eq!(other.verify("user", &signed), None);
+    }
+
+    #[test]
+    fn decrypts_private_values() {
+        let jar = CookieJar::new(SECRET).unwrap();
+        let encrypted = jar.encrypt(Cookie::new("flash", "Welcome back")).value;
+        assert!(!encrypted.contains("Welcome"));
+        assert_eq!(
+            jar.decrypt("flash", &encrypted),
+            Some("Welcome back".to_owned())
+        );
+        // Fresh nonces, so the same value encrypts differently every time.
+        assert_ne!(
+            jar.encrypt(Cookie::new("flash", "Welcome back")).value,
+            encrypted
+        );
+
+        let mut tampered = BASE64_URL_SAFE_NO_PAD.decode(&encrypted).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        let tampered = BASE64_URL_SAFE_NO_PAD.encode(tampered);
+        assert_eq!(jar.decrypt("flash", &tampered), None);
+        assert_eq!(jar.decrypt("other", &encrypted), None);
+        assert_eq!(jar.decrypt("flash", "AAAA"), None);
+        assert_eq!(jar.decrypt("flash", "not base64!"), None);
+    }
+
+    #[test]
+    fn reads_with_fallback_secrets() {
+        let old = CookieJar::new(OLD_SECRET).unwrap();
+        let signed = old.sign(Cookie::new("user", "42")).value;
+        let encrypted = old.encrypt(Cookie::new("user", "42")).value;
+
+        let mut jar = old.clone();
+        jar.rotate(SECRET).unwrap();
+        assert_eq!(jar.verify("user", &signed), Some("42".to_owned()));
+        assert_eq!(jar.decrypt("user", &encrypted), Some("42".to_owned()));
+        // New cookies are written with the new secret only.
+        let fresh = jar.sign(Cookie::new("user", "42")).value;
+        assert_eq!(old.verify("user", &fresh), None);
+        let new = CookieJar::new(SECRET).unwrap();
+        assert_eq!(new.verify("user", &fresh), Some("42".to_owned()));
+
+        let jar = CookieJar::new(SECRET)
+            .unwrap()
+            .fallback(OLD_SECRET)
+            .unwrap();
+        assert_eq!(jar.verify("user", &signed), Some("42".to_owned()));
+        assert_eq!(jar.decrypt("user", &encrypted), Some("42".to_owned()));
+    }
+}
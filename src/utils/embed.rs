@@ -0,0 +1,189 @@
+use crate::utils::conditional::ETag;
+use crate::utils::file::content_type;
+use crate::utils::request::Request;
+use crate::utils::response::Response;
+use crate::utils::static_files::split_path;
+use crate::utils::status::StatusCode;
+use std::fmt::Write as _;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A file compiled into the executable, with its MIME type and ETag worked out at build time.
+pub struct EmbeddedFile {
+    path: &'static str,
+    contents: &'static [u8],
+    content_type: &'static str,
+    etag: &'static str,
+}
+
+impl EmbeddedFile {
+    /// Used by the code `generate` writes, there is no need to call this by hand.
+    pub const fn new(
+        path: &'static str,
+        contents: &'static [u8],
+        content_type: &'static str,
+        etag: &'static str,
+    ) -> Self {
+        Self {
+            path,
+            contents,
+            content_type,
+            etag,
+        }
+    }
+
+    /// The path relative to the embedded directory, always with `/` separators.
+    pub fn path(&self) -> &'static str {
+        self.path
+    }
+
+    pub fn contents(&self) -> &'static [u8] {
+        self.contents
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+
+    pub fn etag(&self) -> ETag {
+        ETag::parse(self.etag).unwrap_or_else(|| ETag::strong(self.etag))
+    }
+}
+
+/// A directory compiled into the executable, to be served with `Router::serve_embedded`.
+///
+/// The table is produced by calling `generate` from a build script and pulled in with
+/// `include_assets!`:
+///
+/// ```ignore
+/// // build.rs
+/// fn main() {
+///     rust_express::utils::embed::generate("public", "assets.rs").unwrap();
+/// }
+///
+/// // main.rs
+/// static ASSETS: EmbeddedDir = rust_express::include_assets!("assets.rs");
+/// ```
+pub struct EmbeddedDir {
+    files: &'static [EmbeddedFile],
+}
+
+impl EmbeddedDir {
+    /// Used by the code `generate` writes. The files must be sorted by path.
+    pub const fn new(files: &'static [EmbeddedFile]) -> Self {
+        Self { files }
+    }
+
+    /// Looks up a file by its path relative to the embedded directory, e.g. `css/app.css`.
+    pub fn get(&self, path: &str) -> Option<&'static EmbeddedFile> {
+        let files = self.files;
+        let path = path.trim_start_matches('/');
+        files
+            .binary_search_by(|file| file.path.cmp(path))
+            .ok()
+            .map(|index| &files[index])
+    }
+
+    pub fn files(&self) -> &'static [EmbeddedFile] {
+        self.files
+    }
+
+    /// Resolves the part of the path after the mount prefix, the same way `ServeStatic` does:
+    /// directories are answered with their `index.html`, after a redirect adding the trailing slash.
+    pub(crate) fn serve(&self, request: &Request, relative: &str) -> Option<Response> {
+        let segments = match split_path(relative) {
+            Some(segments) => segments,
+            None => return Some(Response::new().error("Bad Request", StatusCode::BAD_REQUEST)),
+        };
+        if segments.iter().any(|segment| segment.starts_with('.')) {
+            return None;
+        }
+        let path = segments.join("/");
+
+        if !path.is_empty() {
+            if let Some(file) = self.get(&path) {
+                return Some(Response::new().embedded(file, StatusCode::OK));
+            }
+        }
+
+        let index = if path.is_empty() {
+            "index.html".to_owned()
+        } else {
+            format!("{}/index.html", path)
+        };
+        let file = self.get(&index)?;
+        if !request.route.ends_with('/') {
+            return Some(Response::new().redirect_permanent(&format!("{}/", request.route)));
+        }
+        Some(Response::new().embedded(file, StatusCode::OK))
+    }
+}
+
+/// Includes the table written by `generate` into the build output directory.
+#[macro_export]
+macro_rules! include_assets {
+    ($file:literal) => {
+        include!(concat!(env!("OUT_DIR"), "/", $file))
+    };
+}
+
+/// Call from a build script to embed every file under `dir` into the executable. Writes an
+/// `EmbeddedDir` expression to `file` inside `OUT_DIR`, for `include_assets!` to pick up.
+/// Files and directories whose name starts with a dot are left out.
+pub fn generate(dir: impl AsRef<Path>, file: &str) -> io::Result<()> {
+    let dir = dir.as_ref().canonicalize()?;
+    let out_dir = std::env::var_os("OUT_DIR")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "OUT_DIR is not set"))?;
+
+    let mut files = Vec::new();
+    collect(&dir, &mut files)?;
+    println!("cargo:rerun-if-changed={}", dir.display());
+
+    let mut entries = Vec::new();
+    for path in files {
+        let relative = path
+            .strip_prefix(&dir)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "File outside of directory"))?
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let contents = std::fs::read(&path)?;
+        entries.push((relative, path, ETag::from_bytes(&contents)));
+    }
+    // `EmbeddedDir::get` relies on the order of the generated table.
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut code = String::from("::rust_express::utils::embed::EmbeddedDir::new(&[\n");
+    for (relative, path, etag) in entries {
+        let _ = writeln!(
+            code,
+            "    ::rust_express::utils::embed::EmbeddedFile::new({:?}, include_bytes!({:?}), {:?}, {:?}),",
+            relative,
+            path.to_string_lossy(),
+            content_type(&path),
+            etag.to_string()
+        );
+    }
+    code.push_str("])\n");
+
+    std::fs::write(Path::new(&out_dir).join(file), code)
+}
+
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    println!("cargo:rerun-if-changed={}", dir.display());
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect(&path, files)?;
+        } else {
+            println!("cargo:rerun-if-changed={}", path.display());
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,588 @@
+use crate::utils::conditional::ETag;
+use crate::utils::connection::{self, with_timeout};
+use crate::utils::helpers::format_http_date;
+use crate::utils::request::Request;
+#[cfg(feature = "http2")]
+use crate::utils::response::BodyStream;
+use crate::utils::response::{Chunks, Response, StreamBody};
+use crate::utils::status::StatusCode;
+use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
+use std::collections::VecDeque;
+use std::fs::Metadata;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// More ranges than this in one request is almost certainly an attempt to make us do busywork.
+const MAX_RANGES: usize = 32;
+
+/// A response body backed by a file on disk. Nothing is read until the response is written,
+/// and then the file is streamed instead of being loaded into memory.
+pub struct FileBody {
+    pub(crate) path: PathBuf,
+    pub(crate) disposition: String,
+}
+
+/// A contiguous byte range `[start, end)` of an open file, sent as a whole response body.
+pub(crate) struct FileRange {
+    file: File,
+    start: u64,
+    end: u64,
+}
+
+/// Opens the file behind a file response and turns it into a streamed body,
+/// honoring `Range` requests for successful responses.
+pub(crate) async fn open(request: &Request, mut response: Response) -> Response {
+    let Some(body) = response.file.take() else {
+        return response;
+    };
+
+    // A sibling only stands in for a file that exists, a stale `app.js.gz` alone is no `app.js`.
+    match tokio::fs::metadata(&body.path).await {
+        Ok(metadata) if metadata.is_file() => {}
+        Ok(_) => return response.error("File not found", StatusCode::NOT_FOUND),
+        Err(err) => return file_error(&response, err),
+    }
+    let variant = precompressed(request, &body.path).await;
+    if variant.is_some() {
+        add_vary(&mut response, "Accept-Encoding");
+    }
+    let (path, encoding) = match variant {
+        Some(Variant::Encoded(path, encoding)) => (path, Some(encoding)),
+        _ => (body.path, None),
+    };
+
+    let file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(err) => return file_error(&response, err),
+    };
+    let metadata = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return response.error("File not found", StatusCode::NOT_FOUND),
+        Err(err) => return file_error(&response, err),
+    };
+    let length = metadata.len();
+    let etag = file_etag(&metadata);
+
+    response
+        .headers
+        .insert("Content-Disposition".to_owned(), body.disposition);
+    if let Some(encoding) = encoding {
+        response
+            .headers
+            .insert("Content-Encoding".to_owned(), encoding.to_owned());
+    }
+    response.set_etag(&etag);
+    if let Ok(modified) = metadata.modified() {
+        response.set_last_modified(modified);
+    }
+    if response.status != StatusCode::OK {
+        return send_range(response, file, 0, length);
+    }
+    response
+        .headers
+        .insert("Accept-Ranges".to_owned(), "bytes".to_owned());
+
+    let ranges = match request.header("range") {
+        Some(range) if request.is_read() && if_range_matches(request, &etag, &metadata) => {
+            parse_range(range, length)
+        }
+        _ => None,
+    };
+
+    match ranges {
+        None => send_range(response, file, 0, length),
+        Some(ranges) if ranges.is_empty() => {
+            let mut response =
+                response.error("Range Not Satisfiable", StatusCode::RANGE_NOT_SATISFIABLE);
+            response.headers.remove("Content-Encoding");
+            response
+                .headers
+                .insert("Content-Range".to_owned(), format!("bytes */{}", length));
+            response
+        }
+        Some(ranges) if ranges.len() == 1 => {
+            let (start, end) = ranges[0];
+            response.status = StatusCode::PARTIAL_CONTENT;
+            response.headers.insert(
+                "Content-Range".to_owned(),
+                format!("bytes {}-{}/{}", start, end - 1, length),
+            );
+            send_range(response, file, start, end)
+        }
+        Some(ranges) => stream_byteranges(response, file, ranges, length),
+    }
+}
+
+/// Precompressed siblings, tried in this order when the client likes them equally.
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+enum Variant {
+    /// A sibling the client accepts, with its `Content-Encoding`.
+    Encoded(PathBuf, &'static str),
+    /// Siblings exist but the client accepts none of them, so the original is sent.
+    Identity,
+}
+
+/// Looks for `file.br` or `file.gz` next to the file. `None` means there are no precompressed
+/// siblings at all and the response doesn't depend on `Accept-Encoding`.
+async fn precompressed(request: &Request, path: &Path) -> Option<Variant> {
+    let accept = request.header("accept-encoding").unwrap_or("");
+    let mut found = false;
+    let mut best: Option<(f32, PathBuf, &'static str)> = None;
+    for (encoding, extension) in PRECOMPRESSED {
+        let mut sibling = path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(extension);
+        let sibling = PathBuf::from(sibling);
+        // Symlinks are skipped so a sibling can't point outside a static root.
+        if !tokio::fs::symlink_metadata(&sibling)
+            .await
+            .is_ok_and(|metadata| metadata.is_file())
+        {
+            continue;
+        }
+        found = true;
+
+        let quality = encoding_quality(accept, encoding);
+        if quality > 0.0 && best.as_ref().is_none_or(|(best, _, _)| quality > *best) {
+            best = Some((quality, sibling, encoding));
+        }
+    }
+
+    match best {
+        Some((_, sibling, encoding)) => Some(Variant::Encoded(sibling, encoding)),
+        None => found.then_some(Variant::Identity),
+    }
+}
+
+/// The `q` value the `Accept-Encoding` header gives a coding, `0` when it isn't acceptable.
+fn encoding_quality(accept: &str, encoding: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(encoding)
+            || (encoding == "gzip" && name.eq_ignore_ascii_case("x-gzip"))
+        {
+            return quality;
+        }
+        if name == "*" {
+            wildcard = quality;
+        }
+    }
+    wildcard
+}
+
+fn add_vary(response: &mut Response, header: &str) {
+    let vary = match response.header("vary") {
+        Some(vary)
+            if vary
+                .split(',')
+                .any(|name| name.trim().eq_ignore_ascii_case(header)) =>
+        {
+            return
+        }
+        Some(vary) => format!("{}, {}", vary, header),
+        None => header.to_owned(),
+    };
+    response
+        .headers
+        .retain(|name, _| !name.eq_ignore_ascii_case("vary"));
+    response.headers.insert("Vary".to_owned(), vary);
+}
+
+/// Sends several ranges as a `multipart/byteranges` body, each part carrying its own `Content-Range`.
+fn stream_byteranges(
+    mut response: Response,
+    file: File,
+    ranges: Vec<(u64, u64)>,
+    length: u64,
+) -> Response {
+    let boundary = format!("{:016x}", boundary_seed());
+    let mut parts = VecDeque::new();
+    let mut total = 0;
+    for (i, (start, end)) in ranges.into_iter().enumerate() {
+        let header = format!(
+            "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            if i == 0 { "" } else { "\r\n" },
+            boundary,
+            response.content_type,
+            start,
+            end - 1,
+            length
+        );
+        total += header.len() as u64 + (end - start);
+        parts.push_back(Part {
+            header: Bytes::from(header),
+            start,
+            end,
+        });
+    }
+    let trailer = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+    total += trailer.len() as u64;
+
+    response.status = StatusCode::PARTIAL_CONTENT;
+    response.content_type = format!("multipart/byteranges; boundary={}", boundary);
+    response.content_length = total as usize;
+    response.stream = Some(StreamBody {
+        chunks: Chunks::Stream(Box::pin(
+            read_parts(file, parts, Some(trailer)).map_err(Into::into),
+        )),
+        length: Some(total),
+    });
+    response
+}
+
+fn send_range(mut response: Response, file: File, start: u64, end: u64) -> Response {
+    response.content_length = (end - start) as usize;
+    response.stream = Some(StreamBody {
+        chunks: Chunks::File(FileRange { file, start, end }),
+        length: Some(end - start),
+    });
+    response
+}
+
+impl FileRange {
+    /// The range read chunk by chunk, for connections that can't take it with `write_range`.
+    #[cfg(feature = "http2")]
+    pub(crate) fn into_chunks(self) -> BodyStream {
+        let part = Part {
+            header: Bytes::new(),
+            start: self.start,
+            end: self.end,
+        };
+        Box::pin(read_parts(self.file, VecDeque::from([part]), None).map_err(Into::into))
+    }
+}
+
+/// Writes a file range to the socket, with `sendfile(2)` for plain TCP on Linux and a
+/// buffered copy everywhere else or when the kernel refuses.
+pub(crate) async fn write_range(
+    stream: &mut connection::Stream,
+    range: FileRange,
+    write_timeout: Duration,
+) -> std::io::Result<()> {
+    let FileRange { file, start, end } = range;
+
+    #[cfg(target_os = "linux")]
+    let (file, start) = match stream {
+        connection::Stream::Tcp(tcp) => {
+            let file = file.into_std().await;
+            let mut offset = start;
+            if crate::utils::sendfile::send(tcp, &file, &mut offset, end, write_timeout).await? {
+                return Ok(());
+            }
+            (File::from_std(file), offset)
+        }
+        // TLS has to encrypt in userspace, the kernel can't send the file as it is.
+        _ => (file, start),
+    };
+
+    copy_range(stream, file, start, end, write_timeout).await
+}
+
+async fn copy_range(
+    stream: &mut connection::Stream,
+    mut file: File,
+    start: u64,
+    end: u64,
+    write_timeout: Duration,
+) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut remaining = end - start;
+    let mut buffer = vec![0u8; CHUNK_SIZE.min(remaining as usize)];
+    while remaining > 0 {
+        let want = CHUNK_SIZE.min(remaining as usize);
+        let n = file.read(&mut buffer[..want]).await?;
+        if n == 0 {
+            return Err(truncated());
+        }
+        with_timeout(write_timeout, stream.write_all(&buffer[..n])).await?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+pub(crate) fn file_error(response: &Response, err: std::io::Error) -> Response {
+    match err.kind() {
+        ErrorKind::NotFound => response.error("File not found", StatusCode::NOT_FOUND),
+        ErrorKind::PermissionDenied => response.error("Forbidden", StatusCode::FORBIDDEN),
+        _ => {
+            eprintln!("Error while opening file! {}", err);
+            response.error(
+                "Error while reading file",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+/// A byte range `[start, end)` of the file, preceded by a multipart header when there is one.
+struct Part {
+    header: Bytes,
+    start: u64,
+    end: u64,
+}
+
+struct PartReader {
+    file: File,
+    parts: VecDeque<Part>,
+    remaining: u64,
+    trailer: Option<Bytes>,
+}
+
+fn read_parts(
+    file: File,
+    parts: VecDeque<Part>,
+    trailer: Option<Bytes>,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    let reader = PartReader {
+        file,
+        parts,
+        remaining: 0,
+        trailer,
+    };
+    futures::stream::try_unfold(reader, |mut reader| async move {
+        if reader.remaining > 0 {
+            let mut buffer = vec![0u8; CHUNK_SIZE.min(reader.remaining as usize)];
+            let n = reader.file.read(&mut buffer).await?;
+            if n == 0 {
+                return Err(truncated());
+            }
+            buffer.truncate(n);
+            reader.remaining -= n as u64;
+            return Ok(Some((Bytes::from(buffer), reader)));
+        }
+
+        if let Some(part) = reader.parts.pop_front() {
+            reader.file.seek(SeekFrom::Start(part.start)).await?;
+            reader.remaining = part.end - part.start;
+            return Ok(Some((part.header, reader)));
+        }
+
+        match reader.trailer.take() {
+            Some(trailer) => Ok(Some((trailer, reader))),
+            None => Ok(None),
+        }
+    })
+}
+
+// The file shrank since we looked at its length, the promised body can't be produced.
+fn truncated() -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "File was truncated while being sent",
+    )
+}
+
+/// Parses a `Range: bytes=...` header into `[start, end)` pairs clipped to the file length.
+/// `None` means the header should be ignored and the whole file sent, an empty list means
+/// none of the ranges can be satisfied.
+fn parse_range(header: &str, length: u64) -> Option<Vec<(u64, u64)>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let (first, last) = spec.trim().split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty() {
+            let suffix = parse_position(last)?;
+            (suffix > 0 && length > 0).then(|| (length.saturating_sub(suffix), length))
+        } else {
+            let start = parse_position(first)?;
+            let end = if last.is_empty() {
+                length
+            } else {
+                let last = parse_position(last)?;
+                if last < start {
+                    return None;
+                }
+                last.saturating_add(1).min(length)
+            };
+            (start < length).then_some((start, end))
+        };
+        ranges.extend(range);
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+    // Overlapping ranges like `0-,0-,0-` would send the file many times over in one
+    // response. Whoever asks for them gets it once, whole.
+    let mut sorted = ranges.clone();
+    sorted.sort_unstable();
+    if sorted.windows(2).any(|pair| pair[1].0 < pair[0].1) {
+        return None;
+    }
+    Some(ranges)
+}
+
+fn parse_position(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// A range request only applies if the `If-Range` validator, when present, still matches the file.
+fn if_range_matches(request: &Request, etag: &ETag, metadata: &Metadata) -> bool {
+    match request.header("if-range") {
+        None => true,
+        Some(validator) => match ETag::parse(validator) {
+            Some(validator) => validator.strong_eq(etag),
+            None => metadata
+                .modified()
+                .is_ok_and(|modified| format_http_date(modified) == validator.trim()),
+        },
+    }
+}
+
+/// A strong tag from the modification time and size, the same scheme nginx uses.
+fn file_etag(metadata: &Metadata) -> ETag {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_nanos())
+        .unwrap_or(0);
+    ETag::strong(&format!("{:x}-{:x}", modified, metadata.len()))
+}
+
+fn boundary_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// The `Content-Type` for a file, with a UTF-8 charset for textual types.
+pub(crate) fn content_type(path: &Path) -> String {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let is_text = mime.type_() == mime_guess::mime::TEXT
+        || mime.subtype() == mime_guess::mime::JAVASCRIPT
+        || mime.subtype() == mime_guess::mime::JSON;
+    if is_text {
+        format!("{}; charset=utf-8", mime)
+    } else {
+        mime.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::request::parse_head;
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(vec![(0, 100)]));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(vec![(500, 1000)]));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(vec![(900, 1000)]));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(vec![(0, 1000)]));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some(vec![(900, 1000)]));
+        assert_eq!(parse_range(" bytes= 1 - 2 ", 1000), Some(vec![(1, 3)]));
+    }
+
+    #[test]
+    fn huge_positions_do_not_overflow() {
+        assert_eq!(
+            parse_range("bytes=0-18446744073709551615", 1000),
+            Some(vec![(0, 1000)])
+        );
+        assert_eq!(
+            parse_range("bytes=18446744073709551615-", 1000),
+            Some(vec![])
+        );
+        assert_eq!(parse_range("bytes=0-18446744073709551616", 1000), None);
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_empty() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(parse_range("bytes=-10", 0), Some(vec![]));
+        assert_eq!(parse_range("bytes=2000-3000,5000-", 1000), Some(vec![]));
+    }
+
+    #[test]
+    fn invalid_headers_are_ignored() {
+        for header in [
+            "items=0-1",
+            "bytes=",
+            "bytes=5",
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes=+1-2",
+            "bytes=-+5",
+            "bytes=0-1,junk",
+        ] {
+            assert_eq!(parse_range(header, 1000), None, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn keeps_disjoint_ranges_in_order() {
+        assert_eq!(
+            parse_range("bytes=500-599,0-99", 1000),
+            Some(vec![(500, 600), (0, 100)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-99,100-199", 1000),
+            Some(vec![(0, 100), (100, 200)])
+        );
+    }
+
+    #[test]
+    fn overlapping_or_too_many_ranges_send_the_whole_file() {
+        assert_eq!(
+            parse_range(&format!("bytes={}", vec!["0-"; 32].join(",")), 1000),
+            None
+        );
+        assert_eq!(parse_range("bytes=0-99,50-149", 1000), None);
+        assert_eq!(parse_range("bytes=-100,900-", 1000), None);
+        let many: Vec<_> = (0..33).map(|i| format!("{}-{}", i * 10, i * 10)).collect();
+        assert_eq!(
+            parse_range(&format!("bytes={}", many.join(",")), 1000),
+            None
+        );
+        let allowed: Vec<_> = (0..32).map(|i| format!("{}-{}", i * 10, i * 10)).collect();
+        assert_eq!(
+            parse_range(&format!("bytes={}", allowed.join(",")), 1000).map(|r| r.len()),
+            Some(32)
+        );
+    }
+
+    #[tokio::test]
+    async fn precompressed_siblings_need_the_original() {
+        let root = std::env::temp_dir().join(format!("rust-express-gz-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.js"), "plain").unwrap();
+        std::fs::write(root.join("app.js.gz"), "gzip").unwrap();
+        std::fs::write(root.join("stale.js.gz"), "gzip").unwrap();
+        let request = parse_head("GET / HTTP/1.1\r\nAccept-Encoding: gzip").unwrap();
+        let send = |name: &str| {
+            let path = root.join(name).to_string_lossy().into_owned();
+            open(&request, Response::new().send_file(&path, 200))
+        };
+
+        let found = send("app.js").await;
+        let stale = send("stale.js").await;
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(found.status, StatusCode::OK);
+        assert_eq!(
+            found.headers.get("Content-Encoding").map(String::as_str),
+            Some("gzip")
+        );
+        assert_eq!(stale.status, StatusCode::NOT_FOUND);
+        assert!(!stale.headers.contains_key("Content-Encoding"));
+    }
+}
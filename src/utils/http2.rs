@@ -0,0 +1,498 @@
+use crate::utils::connection::{is_disconnect, with_timeout, Connection, Limits, Peer};
+use crate::utils::request::{parse_head, Request};
+use crate::utils::response::{Chunks, Response};
+use crate::utils::router::Router;
+use crate::utils::status::StatusCode;
+use base64::prelude::*;
+use bytes::{Buf, Bytes};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use http::header::{HeaderName, HeaderValue};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
+
+// Streams one client may have open at the same time.
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// What the client can receive before it says otherwise.
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+const HEADERS_FRAME: u8 = 0x1;
+const END_STREAM: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+/// Headers that only mean something to an HTTP/1 connection and are forbidden in HTTP/2.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Serves an HTTP/2 connection, its streams concurrently, until the client goes away, it idles
+/// for longer than the keep-alive timeout, or the server shuts down.
+pub(crate) async fn serve(
+    connection: Connection,
+    router: &Router,
+    limits: &Limits,
+    mut stopping: watch::Receiver<bool>,
+    mut upgraded: Option<Request>,
+) {
+    let (stream, buffered, peer) = connection.into_parts();
+    let io = Rewind {
+        buffered: Bytes::from(buffered),
+        stream,
+    };
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .max_header_list_size(limits.max_head.try_into().unwrap_or(u32::MAX))
+        .handshake::<_, Bytes>(io);
+    let mut connection = match tokio::time::timeout(limits.header_timeout, handshake).await {
+        Ok(Ok(connection)) => connection,
+        _ => return,
+    };
+
+    let mut streams = FuturesUnordered::new();
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            accepted = connection.accept() => match accepted {
+                Some(Ok((request, respond))) => {
+                    // Stream 1 of an upgraded connection is the request that asked for it.
+                    let upgraded = match respond.stream_id().as_u32() {
+                        1 => upgraded.take(),
+                        _ => None,
+                    };
+                    streams.push(serve_stream(request, respond, router, limits, &peer, upgraded))
+                }
+                Some(Err(err)) => {
+                    if err.get_io().is_some_and(|err| !is_disconnect(err)) {
+                        eprintln!("Error while serving HTTP/2 connection: {}", err);
+                    }
+                    break;
+                }
+                // Every stream is done after a GOAWAY, or the client closed the connection.
+                None => break,
+            },
+            Some(()) = streams.next(), if !streams.is_empty() => {}
+            // Streams in progress get to finish, new ones are refused.
+            _ = stopping.wait_for(|stop| *stop), if !closing => {
+                connection.graceful_shutdown();
+                closing = true;
+            }
+            _ = tokio::time::sleep(limits.keep_alive), if streams.is_empty() && !closing => {
+                connection.graceful_shutdown();
+                closing = true;
+            }
+            // A client that never acknowledges the GOAWAY doesn't get to keep the connection.
+            _ = tokio::time::sleep(limits.write_timeout), if streams.is_empty() && closing => break,
+        }
+    }
+}
+
+async fn serve_stream(
+    request: http::Request<RecvStream>,
+    respond: SendResponse<Bytes>,
+    router: &Router,
+    limits: &Limits,
+    peer: &Peer,
+    upgraded: Option<Request>,
+) {
+    let (parts, mut body) = request.into_parts();
+    let head_only = parts.method == http::Method::HEAD;
+    let request = match upgraded {
+        // Read over HTTP/1.1, body and all, before the connection switched.
+        Some(request) => Ok(request),
+        None => read_request(&parts, &mut body, limits).await,
+    };
+    let response = match request {
+        Ok(mut request) => {
+            peer.apply(&mut request);
+            router.respond(&request, limits.handler_timeout).await
+        }
+        Err(status) => Response::new().error(status.reason_phrase(), status),
+    };
+    if let Err(err) = send_response(respond, response, head_only, limits.write_timeout).await {
+        if !is_disconnect(&err) {
+            eprintln!("Error while sending HTTP/2 response: {}", err);
+        }
+    }
+    // Dropped only now, so a body left unread (e.g. after a 413) is refused with
+    // RST_STREAM(NO_ERROR) once the response is out, instead of the stream being cancelled.
+    drop(body);
+}
+
+async fn read_request(
+    parts: &http::request::Parts,
+    body: &mut RecvStream,
+    limits: &Limits,
+) -> Result<Request, StatusCode> {
+    // Rebuilt as an HTTP/1.1 head, so both protocols end up with exactly the same `Request`.
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let mut head = format!("{} {} HTTP/1.1\r\n", parts.method, path);
+    if let Some(authority) = parts.uri.authority() {
+        head += &format!("host: {}\r\n", authority);
+    }
+    // HTTP/2 clients may split cookies over several headers, HTTP/1 expects them in one.
+    let cookies: Vec<_> = parts
+        .headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    if !cookies.is_empty() {
+        head += &format!("cookie: {}\r\n", cookies.join("; "));
+    }
+    for (name, value) in &parts.headers {
+        if name == http::header::COOKIE
+            || name == http::header::HOST && parts.uri.authority().is_some()
+        {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+    }
+    let mut request = parse_head(&head)?;
+    request.version = "HTTP/2".to_owned();
+    if request.content_length > limits.max_body {
+        return Err(StatusCode::CONTENT_TOO_LARGE);
+    }
+
+    let read_body = async {
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            if data.len() + chunk.len() > limits.max_body {
+                return Err(StatusCode::CONTENT_TOO_LARGE);
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    };
+    let data = tokio::time::timeout(limits.body_timeout, read_body)
+        .await
+        .unwrap_or(Err(StatusCode::REQUEST_TIMEOUT))?;
+    request.set_body(&data);
+    Ok(request)
+}
+
+async fn send_response(
+    mut respond: SendResponse<Bytes>,
+    mut response: Response,
+    head_only: bool,
+    write_timeout: Duration,
+) -> io::Result<()> {
+    let no_body =
+        response.status == StatusCode::NO_CONTENT || response.status == StatusCode::NOT_MODIFIED;
+    let (chunks, length) = match response.stream.take() {
+        Some(body) => (Some(body.chunks), body.length),
+        None => (None, Some(response.body_text().len() as u64)),
+    };
+
+    let mut head = http::Response::new(());
+    *head.status_mut() = http::StatusCode::from_u16(response.status.as_u16())
+        .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR);
+    let headers = head.headers_mut();
+    let mut add = |name: &str, value: &str| match (
+        HeaderName::from_bytes(name.as_bytes()),
+        HeaderValue::from_str(value),
+    ) {
+        (Ok(name), Ok(value)) => {
+            headers.append(name, value);
+        }
+        _ => eprintln!("Dropping invalid response header {}", name),
+    };
+    if !no_body {
+        add("content-type", &response.content_type);
+        if let Some(length) = length {
+            add("content-length", &length.to_string());
+        }
+    }
+    for (name, value) in &response.headers {
+        if !CONNECTION_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            add(name, value);
+        }
+    }
+    for cookie in response.cookies.values() {
+        add("set-cookie", &cookie.to_header_value());
+    }
+
+    // HTTP/1 clients skip the body of a HEAD response, HTTP/2 treats it as an error.
+    if no_body || head_only || length == Some(0) {
+        respond.send_response(head, true).map_err(to_io)?;
+        return Ok(());
+    }
+    let mut send = respond.send_response(head, false).map_err(to_io)?;
+    let mut chunks = match chunks {
+        None => {
+            let body = Bytes::from(response.body_text());
+            return send_data(&mut send, body, true, write_timeout).await;
+        }
+        Some(Chunks::Stream(chunks)) => chunks,
+        Some(Chunks::File(range)) => range.into_chunks(),
+    };
+    // The client checks the body against the announced `content-length`.
+    let mut remaining = length;
+    while remaining != Some(0) {
+        let Some(chunk) = chunks.next().await else {
+            break;
+        };
+        let mut chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                // Resetting the stream tells the client the body is incomplete.
+                send.send_reset(h2::Reason::INTERNAL_ERROR);
+                return Err(io::Error::other(err));
+            }
+        };
+        if let Some(ref mut remaining) = remaining {
+            if chunk.len() as u64 > *remaining {
+                eprintln!("Response body stream is longer than its Content-Length, cutting it off");
+                chunk.truncate(*remaining as usize);
+            }
+            *remaining -= chunk.len() as u64;
+        }
+        if !chunk.is_empty() {
+            send_data(&mut send, chunk, false, write_timeout).await?;
+        }
+    }
+    if remaining.is_some_and(|remaining| remaining > 0) {
+        send.send_reset(h2::Reason::INTERNAL_ERROR);
+        return Err(io::Error::other(
+            "Response body stream ended before its Content-Length",
+        ));
+    }
+    send.send_data(Bytes::new(), true).map_err(to_io)
+}
+
+/// Sends the data as the client's flow control window allows, so a slow reader holds
+/// up the producer instead of the data piling up in memory.
+async fn send_data(
+    send: &mut SendStream<Bytes>,
+    mut data: Bytes,
+    end_of_stream: bool,
+    write_timeout: Duration,
+) -> io::Result<()> {
+    while data.has_remaining() {
+        send.reserve_capacity(data.len());
+        let capacity = with_timeout(write_timeout, async {
+            match std::future::poll_fn(|cx| send.poll_capacity(cx)).await {
+                Some(capacity) => capacity.map_err(to_io),
+                None => Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "Stream was closed",
+                )),
+            }
+        })
+        .await?;
+        let chunk = data.split_to(capacity.min(data.len()));
+        let last = end_of_stream && data.is_empty();
+        send.send_data(chunk, last).map_err(to_io)?;
+    }
+    Ok(())
+}
+
+/// The HEADERS frame opening stream 1 for a request asking for `Upgrade: h2c`, or `None` if
+/// it doesn't ask for it properly. Only what HTTP/2 requires of a request is in there, the
+/// request itself is answered as it was read.
+pub(crate) fn upgrade_headers(request: &Request) -> Option<Vec<u8>> {
+    let has = |header: &str, token: &str| {
+        request.header(header).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    if request.version != "HTTP/1.1"
+        || !has("upgrade", "h2c")
+        || !has("connection", "upgrade")
+        || !has("connection", "http2-settings")
+        || request.method == "CONNECT"
+    {
+        return None;
+    }
+    // The payload of a SETTINGS frame, six bytes per setting.
+    let settings = BASE64_URL_SAFE_NO_PAD
+        .decode(request.header("http2-settings")?.trim_end_matches('='))
+        .ok()?;
+    if settings.len() % 6 != 0 {
+        return None;
+    }
+
+    // The target as sent, with the query, unless it's absolute or `*`.
+    let path = request.raw.split_whitespace().nth(1)?;
+    if !path.starts_with('/') {
+        return None;
+    }
+    let mut block = Vec::new();
+    hpack_literal(&mut block, ":method", &request.method);
+    hpack_literal(&mut block, ":scheme", "http");
+    hpack_literal(&mut block, ":path", path);
+    if http::uri::Authority::try_from(request.host.as_str()).is_ok() {
+        hpack_literal(&mut block, ":authority", &request.host);
+    }
+    // Larger would need CONTINUATION frames, and nobody upgrades with a path that long.
+    if block.len() > MAX_FRAME_SIZE {
+        return None;
+    }
+
+    let mut frame = (block.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.push(HEADERS_FRAME);
+    frame.push(END_STREAM | END_HEADERS);
+    frame.extend_from_slice(&1u32.to_be_bytes());
+    frame.extend(block);
+    Some(frame)
+}
+
+/// A header as a literal that isn't added to the dynamic table, so the client's own header
+/// compression stays in sync with ours.
+fn hpack_literal(block: &mut Vec<u8>, name: &str, value: &str) {
+    block.push(0x00);
+    for string in [name, value] {
+        // A 7 bit prefixed integer, continued in 7 bit groups from 127 on.
+        let mut length = string.len();
+        if length < 0x7f {
+            block.push(length as u8);
+        } else {
+            block.push(0x7f);
+            length -= 0x7f;
+            while length >= 0x80 {
+                block.push((length % 0x80) as u8 | 0x80);
+                length /= 0x80;
+            }
+            block.push(length as u8);
+        }
+        block.extend_from_slice(string.as_bytes());
+    }
+}
+
+/// Resets and GOAWAYs are the client going away, like a closed socket in HTTP/1.
+fn to_io(err: h2::Error) -> io::Error {
+    let kind = match err.get_io() {
+        Some(io) => io.kind(),
+        None if err.is_reset() || err.is_go_away() => io::ErrorKind::ConnectionReset,
+        None => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, err)
+}
+
+/// The socket with the bytes already read while looking for the preface put back in front.
+struct Rewind<T> {
+    buffered: Bytes,
+    stream: T,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rewind<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.buffered.has_remaining() {
+            let length = self.buffered.len().min(buf.remaining());
+            let chunk = self.buffered.split_to(length);
+            buf.put_slice(&chunk);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rewind<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::request::parse_head;
+
+    fn upgrade(lines: &[&str]) -> Option<Vec<u8>> {
+        let mut head = "GET /a?b=c HTTP/1.1\r\nHost: example.com".to_owned();
+        for line in lines {
+            head += "\r\n";
+            head += line;
+        }
+        upgrade_headers(&parse_head(&head).unwrap())
+    }
+
+    #[test]
+    fn builds_headers_frame_for_upgrade() {
+        let frame = upgrade(&[
+            "Connection: Upgrade, HTTP2-Settings",
+            "Upgrade: h2c",
+            "HTTP2-Settings: AAMAAABkAAQAAP__",
+        ])
+        .unwrap();
+        let block = &frame[9..];
+        assert_eq!(frame[..3], (block.len() as u32).to_be_bytes()[1..]);
+        assert_eq!(
+            frame[3..9],
+            [HEADERS_FRAME, END_STREAM | END_HEADERS, 0, 0, 0, 1]
+        );
+
+        let mut expected = Vec::new();
+        for (name, value) in [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/a?b=c"),
+            (":authority", "example.com"),
+        ] {
+            expected.push(0);
+            expected.push(name.len() as u8);
+            expected.extend_from_slice(name.as_bytes());
+            expected.push(value.len() as u8);
+            expected.extend_from_slice(value.as_bytes());
+        }
+        assert_eq!(block, expected);
+    }
+
+    #[test]
+    fn ignores_incomplete_upgrades() {
+        let settings = "HTTP2-Settings: AAMAAABkAAQAAP__";
+        let connection = "Connection: Upgrade, HTTP2-Settings";
+        assert!(upgrade(&[connection, settings]).is_none());
+        assert!(upgrade(&["Upgrade: h2c", settings]).is_none());
+        assert!(upgrade(&["Connection: Upgrade", "Upgrade: h2c", settings]).is_none());
+        assert!(upgrade(&[connection, "Upgrade: h2c"]).is_none());
+        assert!(upgrade(&[connection, "Upgrade: websocket", settings]).is_none());
+        // Not a whole number of settings, and two headers joined into an invalid one.
+        assert!(upgrade(&[connection, "Upgrade: h2c", "HTTP2-Settings: AAMA"]).is_none());
+        assert!(upgrade(&[connection, "Upgrade: h2c", settings, settings]).is_none());
+    }
+
+    #[test]
+    fn encodes_long_lengths() {
+        let mut block = Vec::new();
+        hpack_literal(&mut block, ":path", &"a".repeat(200));
+        // 200 is 127 in the prefix, then 73.
+        assert_eq!(block[..9], [0, 5, b':', b'p', b'a', b't', b'h', 0x7f, 73]);
+        assert_eq!(block.len(), 9 + 200);
+    }
+}
@@ -4,7 +4,7 @@ use std::collections::HashMap;
 
 pub type MiddlewareData = HashMap<String, String>;
 
-type Middleware = Box<dyn Fn(&Request, &mut MiddlewareData) -> () + Send + Sync>;
+type Middleware = Box<dyn Fn(&Request, &mut MiddlewareData) + Send + Sync>;
 type AsyncMiddleware = Box<
     dyn for<'a> Fn(&'a Request, &'a mut MiddlewareData) -> BoxFuture<'a, ()> + Send + Sync,
 >;
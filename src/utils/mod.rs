@@ -3,4 +3,19 @@ pub mod response;
 pub mod router;
 pub mod websocket;
 pub mod middleware;
+mod autoindex;
+pub mod conditional;
+pub(crate) mod connection;
+pub mod cookie;
+pub mod cookie_jar;
+pub mod embed;
+pub mod file;
+#[cfg(feature = "http2")]
+pub(crate) mod http2;
+pub mod session;
+pub mod sse;
+pub mod static_files;
+pub mod status;
+#[cfg(target_os = "linux")]
+mod sendfile;
 mod helpers;
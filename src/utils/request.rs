@@ -1,7 +1,13 @@
+use crate::tls::ClientCertificate;
+#[cfg(unix)]
+use crate::unix::PeerCredentials;
+use crate::utils::cookie::parse_cookie_header;
+use crate::utils::cookie_jar::CookieJar;
 use crate::utils::helpers::{parse_body, parse_query_params};
+use crate::utils::session::Session;
+use crate::utils::status::StatusCode;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tokio::io::AsyncReadExt;
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Request {
@@ -11,10 +17,25 @@ pub struct Request {
     pub user_agent: String,
     pub accept: String,
     pub connection: String,
+    pub version: String,
+    /// The request as received, head and body, with a chunked body already decoded.
     pub raw: String,
     pub content_length: usize,
+    pub headers: HashMap<String, String>,
     pub body: HashMap<String, String>,
     pub query: HashMap<String, String>,
+    /// The certificate the client authenticated with, over TLS with `TlsConfig::client_ca`.
+    #[serde(skip)]
+    pub client_certificate: Option<ClientCertificate>,
+    /// The process that sent the request, when it came in over a Unix socket.
+    #[cfg(unix)]
+    #[serde(skip)]
+    pub peer_credentials: Option<PeerCredentials>,
+    /// The name of the listener the request came in on, see `Listener::name`.
+    #[serde(skip)]
+    pub listener: String,
+    #[serde(skip)]
+    session: Session,
 }
 
 impl Request {
@@ -26,60 +47,342 @@ impl Request {
             user_agent: "".to_owned(),
             accept: "".to_owned(),
             connection: "keep-alive".to_owned(),
+            version: "HTTP/1.1".to_owned(),
             content_length: 0,
             raw: "".to_owned(),
+            headers: HashMap::new(),
             body: HashMap::new(),
             query: HashMap::new(),
+            client_certificate: None,
+            #[cfg(unix)]
+            peer_credentials: None,
+            listener: "".to_owned(),
+            session: Session::default(),
         }
     }
+
+    /// Looks up a request header by name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(String::as_str)
+    }
+
+    /// Cookies sent by the client in the `Cookie` header.
+    pub fn cookies(&self) -> HashMap<String, String> {
+        self.header("cookie")
+            .map(parse_cookie_header)
+            .unwrap_or_default()
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().remove(name)
+    }
+
+    /// A cookie set with `Response::set_signed_cookie`, or `None` if it is missing or was tampered with.
+    pub fn signed_cookie(&self, jar: &CookieJar, name: &str) -> Option<String> {
+        jar.verify(name, &self.cookie(name)?)
+    }
+
+    /// A cookie set with `Response::set_private_cookie`, or `None` if it is missing or was tampered with.
+    pub fn private_cookie(&self, jar: &CookieJar, name: &str) -> Option<String> {
+        jar.decrypt(name, &self.cookie(name)?)
+    }
+
+    /// The session for this request. Without `App::sessions` it is a throwaway session that is never stored.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// The id of the last server-sent event the client received, sent when an `EventSource` reconnects.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.header("last-event-id")
+    }
+}
+
+impl Default for Request {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-pub async fn parse_request_data(stream: &mut tokio::net::TcpStream) -> Request {
-    stream
-        .readable()
-        .await
-        .expect("stream failed to be readable");
-    let mut buffer = [0u8; 4096];
-    let n = stream
-        .read(&mut buffer)
-        .await
-        .expect("Failed to read request buffer");
-    let request_data = String::from_utf8_lossy(&buffer[..n]).to_string();
+/// Parses the request line and headers, everything up to the blank line. The body is read
+/// separately by the connection, which knows how long it is from these headers.
+pub(crate) fn parse_head(head: &str) -> Result<Request, StatusCode> {
     let mut request = Request::new();
-    request.raw = request_data.clone();
-    let mut lines = request_data.split("\r\n");
+    let mut lines = head.split("\r\n");
 
-    if let Some(first_line) = lines.next() {
-        let mut parts = first_line.split_whitespace();
-        request.method = parts.next().unwrap_or("GET").to_string();
-        let full_route = parts.next().unwrap_or("/");
-        let mut route_parts = full_route.splitn(2, '?');
-        request.route = route_parts.next().unwrap_or("/").to_string();
-        let query_string = route_parts.next().unwrap_or("");
-        request.query = parse_query_params(query_string);
+    let first_line = lines.next().unwrap_or("");
+    let mut parts = first_line.split_whitespace();
+    let (Some(method), Some(full_route), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    match version {
+        "HTTP/1.0" | "HTTP/1.1" => {}
+        _ if version.starts_with("HTTP/") => return Err(StatusCode::HTTP_VERSION_NOT_SUPPORTED),
+        _ => return Err(StatusCode::BAD_REQUEST),
     }
+    request.method = method.to_string();
+    request.version = version.to_string();
+    let mut route_parts = full_route.splitn(2, '?');
+    request.route = route_parts.next().unwrap_or("/").to_string();
+    let query_string = route_parts.next().unwrap_or("");
+    request.query = parse_query_params(query_string);
 
-    for line in lines.by_ref() {
+    for line in lines {
         if line.is_empty() {
             break;
         }
 
-        let mut header_parts = line.splitn(2, ": ");
-        let header_key = header_parts.next().unwrap_or("").to_lowercase();
-        let header_value = header_parts.next().unwrap_or("").to_string();
-
+        let Some((header_key, header_value)) = line.split_once(':') else {
+            return Err(StatusCode::BAD_REQUEST);
+        };
+        // Not trimmed: a proxy may ignore `Transfer-Encoding : chunked`, or a folded line
+        // starting with whitespace, and frame the body differently than we would.
+        if !is_token(header_key) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        let header_key = header_key.to_lowercase();
+        let header_value = header_value.trim().to_string();
         match header_key.as_str() {
-            "host" => request.host = header_value,
-            "user-agent" => request.user_agent = header_value,
-            "accept" => request.accept = header_value,
-            "connection" => request.connection = header_value,
-            "content-length" => request.content_length = header_value.parse().unwrap_or(0),
+            "host" => request.host = header_value.clone(),
+            "user-agent" => request.user_agent = header_value.clone(),
+            "accept" => request.accept = header_value.clone(),
+            "connection" => request.connection = header_value.clone(),
+            "content-length" => {
+                let length = parse_content_length(&header_value)?;
+                // Two different lengths mean two parties could disagree on where the body ends.
+                if request.headers.contains_key("content-length")
+                    && request.content_length != length
+                {
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                request.content_length = length;
+            }
+            // A list header, repeating it is the same as one header with the values joined.
+            // `HTTP2-Settings` isn't one, but joined a second one makes the value invalid
+            // instead of silently replacing the first.
+            "transfer-encoding" | "http2-settings" => {
+                if let Some(previous) = request.headers.remove(&header_key) {
+                    let joined = format!("{}, {}", previous, header_value);
+                    request.headers.insert(header_key, joined);
+                    continue;
+                }
+            }
             _ => {}
         }
+        request.headers.insert(header_key, header_value);
+    }
+
+    if let Some(encoding) = request.header("transfer-encoding") {
+        // With both, a proxy in front may pick the other one and see a different request
+        // than ours (request smuggling).
+        if request.headers.contains_key("content-length") {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        // Only `chunked` tells where the body ends, so it has to be the last coding, and
+        // it can't be applied twice.
+        let mut codings = encoding.split(',').map(str::trim).rev();
+        let last_is_chunked = codings
+            .next()
+            .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+        if !last_is_chunked || codings.any(|coding| coding.eq_ignore_ascii_case("chunked")) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    // The head comes without the blank line that ends it.
+    request.raw = format!("{}\r\n\r\n", head.trim_end_matches("\r\n"));
+    Ok(request)
+}
+
+/// A header name, one or more of the characters RFC 9110 allows in a token.
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Only plain digits, `+5` or `5, 5` are not lengths anyone should have to guess about.
+fn parse_content_length(value: &str) -> Result<usize, StatusCode> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    value.parse().map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+impl Request {
+    /// Fills in the body once the connection has read it.
+    pub(crate) fn set_body(&mut self, body: &[u8]) {
+        let body = String::from_utf8_lossy(body);
+        self.raw.push_str(&body);
+        self.body = parse_body(&body);
+    }
+
+    /// GET or HEAD. HEAD is answered like GET, only without the body.
+    pub(crate) fn is_read(&self) -> bool {
+        self.method == "GET" || self.method == "HEAD"
+    }
+
+    /// Whether the body is sent in chunks. `parse_head` only lets through transfer codings
+    /// that end in `chunked`.
+    pub(crate) fn is_chunked(&self) -> bool {
+        self.headers.contains_key("transfer-encoding")
+    }
+
+    /// Whether the client is willing to send another request on this connection.
+    /// HTTP/1.1 connections persist unless closed, HTTP/1.0 ones only when asked to.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("connection").unwrap_or("");
+        let has = |token: &str| {
+            connection
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        };
+        if has("close") {
+            return false;
+        }
+        self.version != "HTTP/1.0" || has("keep-alive")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(headers: &[&str]) -> Result<Request, StatusCode> {
+        let mut head = "POST /submit?a=1&b=two HTTP/1.1".to_owned();
+        for header in headers {
+            head += "\r\n";
+            head += header;
+        }
+        parse_head(&head)
     }
 
-    let body = lines.collect::<Vec<&str>>().join("\r\n");
-    let parsed_body = parse_body(&body);
-    request.body = parsed_body;
-    request
+    #[test]
+    fn parses_request_line_and_headers() {
+        let request = parse(&["Host: example.com", "X-Custom:  spaced  "]).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.route, "/submit");
+        assert_eq!(request.query.get("b").map(String::as_str), Some("two"));
+        assert_eq!(request.host, "example.com");
+        assert_eq!(request.header("x-custom"), Some("spaced"));
+        assert!(request.raw.ends_with("X-Custom:  spaced  \r\n\r\n"));
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        assert_eq!(parse_head("GET /").err(), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(
+            parse_head("GET / HTTP/1.1 extra").err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            parse_head("GET / FTP/1.0").err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            parse_head("GET / HTTP/2.0").err(),
+            Some(StatusCode::HTTP_VERSION_NOT_SUPPORTED)
+        );
+        assert_eq!(parse(&["no colon"]).err(), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(
+            parse(&[": empty name"]).err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[test]
+    fn header_names_must_be_tokens() {
+        assert_eq!(
+            parse(&["X-Custom_1.a!: ok"])
+                .unwrap()
+                .header("x-custom_1.a!"),
+            Some("ok")
+        );
+        for headers in [
+            &["Transfer-Encoding : chunked"][..],
+            &["Transfer-Encoding\t: chunked"],
+            &[" Transfer-Encoding: chunked"],
+            // Folded onto the previous line (obs-fold).
+            &["X-Custom: a", " Transfer-Encoding: chunked"],
+            &["X-Custom: a", "\tb"],
+            &["X(Custom): a"],
+            &["X\u{e9}: a"],
+        ] {
+            assert_eq!(
+                parse(headers).err(),
+                Some(StatusCode::BAD_REQUEST),
+                "{:?}",
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn content_length_must_be_unambiguous() {
+        assert_eq!(parse(&["Content-Length: 5"]).unwrap().content_length, 5);
+        assert_eq!(
+            parse(&["Content-Length: 5", "Content-Length: 5"])
+                .unwrap()
+                .content_length,
+            5
+        );
+        for headers in [
+            &["Content-Length: 0", "Content-Length: 5"][..],
+            &["Content-Length: +5"],
+            &["Content-Length: -1"],
+            &["Content-Length: 5, 5"],
+            &["Content-Length: 0x10"],
+            &["Content-Length:"],
+            &["Content-Length: 99999999999999999999999"],
+        ] {
+            assert_eq!(
+                parse(headers).err(),
+                Some(StatusCode::BAD_REQUEST),
+                "{:?}",
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn transfer_encoding_must_end_in_chunked() {
+        for headers in [
+            &["Transfer-Encoding: chunked"][..],
+            &["Transfer-Encoding: CHUNKED"],
+            &["Transfer-Encoding: gzip, chunked"],
+            &["Transfer-Encoding: gzip", "Transfer-Encoding: chunked"],
+        ] {
+            assert!(parse(headers).unwrap().is_chunked(), "{:?}", headers);
+        }
+        for headers in [
+            &["Transfer-Encoding: xchunked"][..],
+            &["Transfer-Encoding: gzip"],
+            &["Transfer-Encoding: chunked, gzip"],
+            &["Transfer-Encoding: chunked", "Transfer-Encoding: gzip"],
+            &["Transfer-Encoding: chunked, chunked"],
+            &["Transfer-Encoding: chunked", "Content-Length: 5"],
+            &["Content-Length: 5", "Transfer-Encoding: chunked"],
+        ] {
+            assert_eq!(
+                parse(headers).err(),
+                Some(StatusCode::BAD_REQUEST),
+                "{:?}",
+                headers
+            );
+        }
+        assert!(!parse(&["Content-Length: 5"]).unwrap().is_chunked());
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version_and_connection_header() {
+        assert!(parse_head("GET / HTTP/1.1").unwrap().keep_alive());
+        assert!(!parse(&["Connection: close"]).unwrap().keep_alive());
+        assert!(!parse_head("GET / HTTP/1.0").unwrap().keep_alive());
+        assert!(parse_head("GET / HTTP/1.0\r\nConnection: Keep-Alive")
+            .unwrap()
+            .keep_alive());
+    }
 }
@@ -1,23 +1,49 @@
-use std::{collections::HashMap, io::ErrorKind};
+use std::{collections::HashMap, pin::Pin};
 
+use crate::utils::conditional::ETag;
+use crate::utils::cookie::Cookie;
+use crate::utils::cookie_jar::CookieJar;
+use crate::utils::embed::EmbeddedFile;
+use crate::utils::file::{content_type, file_error, FileBody, FileRange};
+use crate::utils::helpers::{external_host, format_http_date};
 use crate::utils::request::Request;
-use crate::utils::router::Router;
-use base64::prelude::*;
-use mime_guess::from_path;
+use crate::utils::sse::{Event, SseStream};
+use crate::utils::status::StatusCode;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
 use serde_json::{json, Value};
+use std::convert::Infallible;
 use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+pub type BodyStream = Pin<Box<dyn Stream<Item = Result<Bytes, BoxError>> + Send>>;
+
+/// A response body that is written to the socket chunk by chunk instead of being buffered.
+/// Without a known length the body is sent with `Transfer-Encoding: chunked`.
+pub struct StreamBody {
+    pub(crate) chunks: Chunks,
+    pub(crate) length: Option<u64>,
+}
+
+pub(crate) enum Chunks {
+    Stream(BodyStream),
+    /// A slice of an open file, which can be handed to the kernel instead of read into memory.
+    File(FileRange),
+}
 
 #[allow(dead_code)]
 pub struct Response {
     pub content_type: String,
     pub content_length: usize,
-    pub status_text: String,
-    pub status: i64,
+    pub status: StatusCode,
     pub body: Value,
     pub raw: String,
-    pub cookies: HashMap<String, String>,
-    pub is_file: bool,
+    pub cookies: HashMap<String, Cookie>,
+    pub headers: HashMap<String, String>,
+    pub stream: Option<StreamBody>,
+    pub file: Option<FileBody>,
 }
 
 #[allow(dead_code)]
@@ -26,20 +52,87 @@ impl Response {
         Self {
             content_length: 0,
             content_type: "text/plain".to_owned(),
-            status_text: "OK".to_owned(),
-            status: 200,
+            status: StatusCode::OK,
             body: json!(format!("")),
-            raw: format!("HTTP/1.1 200 OK\r\nContent-type: text/plain\r\n\r\n"),
+            raw: "HTTP/1.1 200 OK\r\nContent-type: text/plain\r\n\r\n".to_owned(),
             cookies: HashMap::new(),
-            is_file: false,
+            headers: HashMap::new(),
+            stream: None,
+            file: None,
+        }
+    }
+
+    /// Looks up a response header by name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn set_etag(&mut self, etag: &ETag) {
+        self.headers.insert("ETag".to_owned(), etag.to_string());
+    }
+
+    pub fn set_last_modified(&mut self, time: SystemTime) {
+        self.headers
+            .insert("Last-Modified".to_owned(), format_http_date(time));
+    }
+
+    /// Adds a strong `ETag` computed from the body, so repeated GETs for unchanged
+    /// content are answered with `304 Not Modified`. Streamed and file bodies are left alone,
+    /// files get their validators when they are opened.
+    pub fn with_etag(mut self) -> Response {
+        if self.stream.is_none() && self.file.is_none() {
+            let etag = ETag::from_bytes(self.body_text().as_bytes());
+            self.set_etag(&etag);
+        }
+        self
+    }
+
+    /// Like `with_etag`, but marks the tag as weak, for bodies that may differ in
+    /// insignificant ways (e.g. key order or whitespace) between equivalent versions.
+    pub fn with_weak_etag(mut self) -> Response {
+        if self.stream.is_none() && self.file.is_none() {
+            let etag = ETag::from_bytes(self.body_text().as_bytes());
+            self.set_etag(&ETag::weak(etag.tag()));
+        }
+        self
+    }
+
+    /// The buffered body as it is sent on the wire.
+    pub(crate) fn body_text(&self) -> String {
+        match self.body.as_str() {
+            Some(text) => text.to_string(),
+            None => self.body.to_string(),
         }
     }
 
-    pub fn text(&self, body: &str, status: i64) -> Response {
+    pub fn set_cookie(&mut self, cookie: Cookie) {
+        self.cookies.insert(cookie.name.clone(), cookie);
+    }
+
+    /// Sets a cookie whose value is readable by the client but carries a signature.
+    pub fn set_signed_cookie(&mut self, jar: &CookieJar, cookie: Cookie) {
+        self.set_cookie(jar.sign(cookie));
+    }
+
+    /// Sets a cookie whose value is encrypted and authenticated.
+    pub fn set_private_cookie(&mut self, jar: &CookieJar, cookie: Cookie) {
+        self.set_cookie(jar.encrypt(cookie));
+    }
+
+    /// Tells the browser to delete a cookie that was set with the default `Path=/` and no domain.
+    /// Cookies set with a custom path or domain need `set_cookie(cookie.expire())` instead.
+    pub fn clear_cookie(&mut self, name: &str) {
+        self.set_cookie(Cookie::new(name, "").expire());
+    }
+
+    pub fn text(&self, body: &str, status: impl Into<StatusCode>) -> Response {
+        let status = status.into();
         Response {
             content_length: body.len(),
             content_type: "text/plain".to_owned(),
-            status_text: "OK".to_owned(),
             status,
             body: json!(body),
             raw: format!(
@@ -48,15 +141,17 @@ impl Response {
                 body
             ),
             cookies: self.cookies.to_owned(),
-            is_file: false,
+            headers: self.headers.to_owned(),
+            stream: None,
+            file: None,
         }
     }
 
-    pub fn json(&self, body: Value, status: i64) -> Response {
+    pub fn json(&self, body: Value, status: impl Into<StatusCode>) -> Response {
+        let status = status.into();
         Response {
             content_length: body.to_string().len(),
             content_type: "application/json".to_owned(),
-            status_text: "OK".to_owned(),
             status,
             body: body.to_owned(),
             raw: format!(
@@ -65,85 +160,225 @@ impl Response {
                 serde_json::to_string(&body.to_owned()).unwrap()
             ),
             cookies: self.cookies.to_owned(),
-            is_file: false,
+            headers: self.headers.to_owned(),
+            stream: None,
+            file: None,
         }
     }
 
-    pub fn error(&self, body: &str, status: i64) -> Response {
+    pub fn error(&self, body: &str, status: impl Into<StatusCode>) -> Response {
+        let status = status.into();
         Response {
             status,
             body: json!(body),
-            status_text: "Internal Server Error".to_owned(),
             content_length: body.len(),
             content_type: "text/plain".to_owned(),
             raw: format!(
-                "HTTP/1.1 {} Internal Server Error\r\nContent-Type: text/plain\r\n\r\n{}",
+                "HTTP/1.1 {}\r\nContent-Type: text/plain\r\n\r\n{}",
                 status,
                 body
             ),
             cookies: self.cookies.to_owned(),
-            is_file: false,
+            headers: self.headers.to_owned(),
+            stream: None,
+            file: None,
         }
     }
 
 
-    pub fn send_file(&self, path: &str, status: i64) -> Response {
-        let filename = path.split("/").last().unwrap();
-        let file_type = from_path(path).first_or_octet_stream().to_string();
-        let mut file_content = String::new();
-
-        match fs::read_to_string(path) {
-            Ok(content) => file_content = content,
-            Err(err) => {
-                if err.kind() == ErrorKind::InvalidData {
-                    let file_bytes = fs::read(path).expect("Error while reading file bytes");
-                    file_content = BASE64_STANDARD.encode(file_bytes)
-                } else {
-                    eprint!("Error while reading file! {}", err.to_string())
-                }
-            }
-        }
-
-        Response {
-            content_length: file_content.len(),
-            content_type: file_type.to_owned(),
-            status_text: "OK".to_owned(),
-            status,
-            body: json!(format!("{filename}:{file_content}")),
-            raw: format!(
-                "HTTP/1.1 {}\r\nContent-Type: {}\r\n\r\n{}",
-                status, file_type, self.body
-            ),
-            cookies: self.cookies.to_owned(),
-            is_file: true,
-        }
+    /// Sends the file at `path` as a download. The file is streamed when the response is written.
+    pub fn send_file(&self, path: &str, status: impl Into<StatusCode>) -> Response {
+        let path = PathBuf::from(path);
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "file".to_owned());
+        self.file(path, &attachment(&filename), status)
     }
 
-    pub fn render(&self, path: &str, status: i64) -> Response {
-        let file_content = fs::read_to_string(path).expect("Error while reading html content");
+    pub fn render(&self, path: &str, status: impl Into<StatusCode>) -> Response {
+        let status = status.into();
+        let file_content = match fs::read_to_string(path) {
+            Ok(file_content) => file_content,
+            Err(err) => return file_error(self, err),
+        };
         Response {
             content_length: file_content.len(),
             content_type: "text/html".to_owned(),
-            status_text: "OK".to_owned(),
             status,
             body: json!(file_content),
             raw: format!(
-                "HTTP/1.1 {} OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
+                "HTTP/1.1 {}\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
                 status,
                 file_content.len(),
                 file_content
             ),
             cookies: self.cookies.to_owned(),
-            is_file: false,
+            headers: self.headers.to_owned(),
+            stream: None,
+            file: None,
         }
     }
 
+    /// A response with a file compiled into the executable, see `EmbeddedDir`.
+    pub fn embedded(&self, file: &'static EmbeddedFile, status: impl Into<StatusCode>) -> Response {
+        let contents = Bytes::from_static(file.contents());
+        let length = contents.len() as u64;
+        let chunks = futures::stream::once(async move { Ok::<_, Infallible>(contents) });
+        let mut response = self.stream_with_length(chunks, length, status);
+        response.content_type = file.content_type().to_owned();
+        response.set_etag(&file.etag());
+        response
+    }
+
+    /// A response streaming the file at `path`, opened asynchronously when the response is written.
+    pub(crate) fn file(
+        &self,
+        path: PathBuf,
+        disposition: &str,
+        status: impl Into<StatusCode>,
+    ) -> Response {
+        let mut response = self.text("", status);
+        response.content_type = content_type(&path);
+        response.file = Some(FileBody {
+            path,
+            disposition: disposition.to_owned(),
+        });
+        response
+    }
+
+    /// Redirects with `302 Found`.
+    pub fn redirect(&self, url: &str) -> Response {
+        self.redirect_with(url, StatusCode::FOUND)
+    }
+
+    /// Redirects with `301 Moved Permanently`.
+    pub fn redirect_permanent(&self, url: &str) -> Response {
+        self.redirect_with(url, StatusCode::MOVED_PERMANENTLY)
+    }
+
+    /// Redirects with `303 See Other`, telling the client to follow up with a GET.
+    pub fn see_other(&self, url: &str) -> Response {
+        self.redirect_with(url, StatusCode::SEE_OTHER)
+    }
+
+    /// Redirects with `307 Temporary Redirect`, keeping the request method and body.
+    pub fn temporary(&self, url: &str) -> Response {
+        self.redirect_with(url, StatusCode::TEMPORARY_REDIRECT)
+    }
+
+    /// Redirects with `308 Permanent Redirect`, keeping the request method and body.
+    pub fn permanent(&self, url: &str) -> Response {
+        self.redirect_with(url, StatusCode::PERMANENT_REDIRECT)
+    }
+
+    /// Redirects to the page in the `Referer` header, or to `fallback` when there is none
+    /// or it points at another host.
+    pub fn back(&self, request: &Request, fallback: &str) -> Response {
+        let referer = request.header("referer").filter(|referer| {
+            match external_host(referer) {
+                Some(host) => host == request.host.to_lowercase(),
+                None => true,
+            }
+        });
+        self.redirect(referer.unwrap_or(fallback))
+    }
+
+    pub fn redirect_with(&self, url: &str, status: impl Into<StatusCode>) -> Response {
+        // A raw newline in the target would let it inject headers of its own.
+        let location = url.replace(['\r', '\n'], "");
+        let mut response = self.text(&format!("Redirecting to {}", location), status);
+        response.headers.insert("Location".to_owned(), location);
+        response
+    }
+
+    pub fn stream<S, E>(&self, stream: S, status: impl Into<StatusCode>) -> Response
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+        E: Into<BoxError> + 'static,
+    {
+        self.stream_body(stream, None, status.into())
+    }
+
+    pub fn stream_with_length<S, E>(
+        &self,
+        stream: S,
+        length: u64,
+        status: impl Into<StatusCode>,
+    ) -> Response
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+        E: Into<BoxError> + 'static,
+    {
+        self.stream_body(stream, Some(length), status.into())
+    }
+
+    /// Sends a `text/event-stream` response. Use `request.last_event_id()` to resume
+    /// a reconnecting client from where it left off.
+    pub fn sse<S>(&self, events: S) -> Response
+    where
+        S: Stream<Item = Event> + Send + 'static,
+    {
+        self.sse_with_keep_alive(events, Duration::from_secs(15))
+    }
+
+    pub fn sse_with_keep_alive<S>(&self, events: S, keep_alive: Duration) -> Response
+    where
+        S: Stream<Item = Event> + Send + 'static,
+    {
+        let mut response = self.stream(SseStream::new(events, keep_alive), StatusCode::OK);
+        response.content_type = "text/event-stream".to_owned();
+        response
+            .headers
+            .insert("Cache-Control".to_owned(), "no-cache".to_owned());
+        response
+    }
+
+    fn stream_body<S, E>(&self, stream: S, length: Option<u64>, status: StatusCode) -> Response
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+        E: Into<BoxError> + 'static,
+    {
+        Response {
+            content_length: length.unwrap_or(0) as usize,
+            content_type: "application/octet-stream".to_owned(),
+            status,
+            body: json!(""),
+            raw: String::new(),
+            cookies: self.cookies.to_owned(),
+            headers: self.headers.to_owned(),
+            stream: Some(StreamBody {
+                chunks: Chunks::Stream(Box::pin(stream.map(|chunk| chunk.map_err(Into::into)))),
+                length,
+            }),
+            file: None,
+        }
+    }
 }
 
-pub async fn handle_response(
-    stream: &mut tokio::net::TcpStream,
-    request: &Request,
-    router: &Router,
-) {
-    router.handle_request(stream, request).await;
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `Content-Disposition` for a download, with an RFC 5987 `filename*` for non-ASCII names.
+fn attachment(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii_graphic() || c == ' ' => c,
+            _ => '_',
+        })
+        .collect();
+    if fallback == filename {
+        format!("attachment; filename=\"{}\"", filename)
+    } else {
+        format!(
+            "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+            fallback,
+            urlencoding::encode(filename)
+        )
+    }
 }
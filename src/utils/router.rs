@@ -1,9 +1,20 @@
+use crate::utils::conditional;
+use crate::utils::connection::{is_disconnect, with_timeout, Stream};
+use crate::utils::embed::EmbeddedDir;
+use crate::utils::file;
+use crate::utils::helpers::external_host;
 use crate::utils::request::Request;
-use crate::utils::response::Response;
+use crate::utils::response::{Chunks, Response};
+use crate::utils::session::Sessions;
+use crate::utils::static_files::{strip_mount, ServeStatic};
+use crate::utils::status::StatusCode;
 use futures::future::BoxFuture;
+use futures::StreamExt;
 use serde_json::json;
 use std::collections::HashMap;
 use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 
 type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
@@ -16,6 +27,22 @@ pub struct Router {
     put_routes: HashMap<String, EitherHandler>,
     delete_routes: HashMap<String, EitherHandler>,
     patch_routes: HashMap<String, EitherHandler>,
+    pub(crate) sessions: Option<Arc<Sessions>>,
+    redirect_hosts: Option<Vec<String>>,
+    static_mounts: Vec<(String, Mount)>,
+    spa_fallback: Option<SpaFallback>,
+    /// Set for listeners that only serve part of the app.
+    pub(crate) prefixes: Option<Vec<String>>,
+}
+
+struct SpaFallback {
+    index: String,
+    excluded: Vec<String>,
+}
+
+enum Mount {
+    Directory(ServeStatic),
+    Embedded(&'static EmbeddedDir),
 }
 
 #[allow(dead_code)]
@@ -33,9 +60,50 @@ impl Router {
             put_routes: HashMap::new(),
             delete_routes: HashMap::new(),
             patch_routes: HashMap::new(),
+            sessions: None,
+            redirect_hosts: None,
+            static_mounts: Vec::new(),
+            spa_fallback: None,
+            prefixes: None,
         }
     }
 
+    /// Serves the files of a directory under `prefix` for GET requests that no route matched.
+    pub fn serve_static(&mut self, prefix: &str, files: ServeStatic) {
+        self.static_mounts
+            .push((prefix.to_string(), Mount::Directory(files)));
+    }
+
+    /// Like `serve_static`, for a directory compiled into the executable.
+    pub fn serve_embedded(&mut self, prefix: &str, files: &'static EmbeddedDir) {
+        self.static_mounts
+            .push((prefix.to_string(), Mount::Embedded(files)));
+    }
+
+    /// Single-page app mode: GET requests from browsers (accepting `text/html`) that no route or
+    /// static file matched get the HTML file at `index`, so the app can do its own routing.
+    /// Paths under the excluded prefixes, like `/api`, and paths with a file extension still 404.
+    pub fn spa_fallback(&mut self, index: &str, excluded_prefixes: &[&str]) {
+        self.spa_fallback = Some(SpaFallback {
+            index: index.to_string(),
+            excluded: excluded_prefixes
+                .iter()
+                .map(|prefix| prefix.to_string())
+                .collect(),
+        });
+    }
+
+    /// Only allow redirects to the request's own host and the given hosts. Responses redirecting
+    /// anywhere else (for example to a `?next=` parameter pointing off-site) become `400 Bad Request`.
+    pub fn restrict_redirects(&mut self, allowed_hosts: &[&str]) {
+        self.redirect_hosts = Some(
+            allowed_hosts
+                .iter()
+                .map(|host| host.to_lowercase())
+                .collect(),
+        );
+    }
+
     pub fn get<F>(&mut self, route: &str, handler: F)
     where
         F: Fn(&Request) -> Response + Send + Sync + 'static,
@@ -131,113 +199,347 @@ impl Router {
         );
     }
 
-    pub async fn handle_request(&self, stream: &mut tokio::net::TcpStream, request: &Request) {
-        let response = match request.method.as_str() {
-            "GET" => {
-                if let Some(handler) = self.get_routes.get(&request.route) {
-                    self.execute_handler(handler, request).await
-                } else {
-                    handle_not_found()
-                }
-            }
-            "POST" => {
-                if let Some(handler) = self.post_routes.get(&request.route) {
-                    self.execute_handler(handler, request).await
-                } else {
-                    handle_not_found()
-                }
-            }
-            "PUT" => {
-                if let Some(handler) = self.put_routes.get(&request.route) {
-                    self.execute_handler(handler, request).await
-                } else {
-                    handle_not_found()
-                }
-            }
-            "DELETE" => {
-                if let Some(handler) = self.delete_routes.get(&request.route) {
-                    self.execute_handler(handler, request).await
-                } else {
-                    handle_not_found()
-                }
+    /// `handle_request`, answering with `503 Service Unavailable` if it takes longer than `limit`.
+    pub(crate) async fn respond(&self, request: &Request, limit: Option<Duration>) -> Response {
+        match limit {
+            Some(limit) => tokio::time::timeout(limit, self.handle_request(request))
+                .await
+                .unwrap_or_else(|_| {
+                    Response::new().error("Service Unavailable", StatusCode::SERVICE_UNAVAILABLE)
+                }),
+            None => self.handle_request(request).await,
+        }
+    }
+
+    /// Runs the request through the routes, static mounts and fallbacks and returns the
+    /// response ready to be written.
+    pub async fn handle_request(&self, request: &Request) -> Response {
+        if let Some(ref prefixes) = self.prefixes {
+            if !prefixes
+                .iter()
+                .any(|prefix| strip_mount(prefix, &request.route).is_some())
+            {
+                return handle_not_found();
             }
+        }
 
-            "PATCH" => {
-                if let Some(handler) = self.patch_routes.get(&request.route) {
-                    self.execute_handler(handler, request).await
-                } else {
-                    handle_not_found()
-                }
+        let routes = match request.method.as_str() {
+            // The response to HEAD is the one to GET, the body is dropped when it is sent.
+            "GET" | "HEAD" => Some(&self.get_routes),
+            "POST" => Some(&self.post_routes),
+            "PUT" => Some(&self.put_routes),
+            "DELETE" => Some(&self.delete_routes),
+            "PATCH" => Some(&self.patch_routes),
+            _ => None,
+        };
+
+        if let Some(ref sessions) = self.sessions {
+            sessions.load(request).await;
+        }
+
+        let mut response = match routes {
+            Some(routes) => match routes.get(&request.route) {
+                Some(handler) => self.execute_handler(handler, request).await,
+                None => match self.serve_static_files(request).await {
+                    Some(response) => response,
+                    None => match self.spa_index(request) {
+                        Some(index) => Response::new().render(index, StatusCode::OK),
+                        None => handle_not_found(),
+                    },
+                },
+            },
+            None => handle_method_not_allowed(),
+        };
+
+        if !self.is_redirect_allowed(request, &response) {
+            response = Response::new()
+                .error("Redirect target is not allowed", StatusCode::BAD_REQUEST);
+        }
+
+        if let Some(ref sessions) = self.sessions {
+            sessions.commit(request, &mut response).await;
+        }
+
+        let response = file::open(request, response).await;
+        conditional::apply(request, response)
+    }
+
+    async fn serve_static_files(&self, request: &Request) -> Option<Response> {
+        if !request.is_read() {
+            return None;
+        }
+        for (prefix, mount) in self.static_mounts.iter() {
+            let Some(relative) = strip_mount(prefix, &request.route) else {
+                continue;
+            };
+            let response = match mount {
+                Mount::Directory(files) => files.serve(request, relative).await,
+                Mount::Embedded(files) => files.serve(request, relative),
+            };
+            if response.is_some() {
+                return response;
             }
-            _ => handle_method_not_allowed(),
+        }
+        None
+    }
+
+    fn spa_index(&self, request: &Request) -> Option<&str> {
+        let spa = self.spa_fallback.as_ref()?;
+        if !request.is_read()
+            || !request
+                .header("accept")
+                .is_some_and(|accept| accept.contains("text/html"))
+        {
+            return None;
+        }
+        if spa
+            .excluded
+            .iter()
+            .any(|prefix| strip_mount(prefix, &request.route).is_some())
+        {
+            return None;
+        }
+        // `/app.js` or `/logo.png` missing is a broken asset, not a client-side route.
+        let last = request.route.rsplit('/').next().unwrap_or("");
+        if last.contains('.') {
+            return None;
+        }
+        Some(&spa.index)
+    }
+
+    fn is_redirect_allowed(&self, request: &Request, response: &Response) -> bool {
+        let (Some(allowed_hosts), Some(location)) =
+            (&self.redirect_hosts, response.header("location"))
+        else {
+            return true;
         };
 
-        stream
-            .write_all(response.as_bytes())
-            .await
-            .expect("Failed to write response back to the client");
+        match external_host(location) {
+            Some(host) => host == request.host.to_lowercase() || allowed_hosts.contains(&host),
+            None => true,
+        }
     }
 
-    async fn execute_handler(&self, handler: &EitherHandler, request: &Request) -> String {
+    async fn execute_handler(&self, handler: &EitherHandler, request: &Request) -> Response {
         match handler {
-            EitherHandler::Sync(sync_handler) => {
-                let mut response = sync_handler(request);
-                handle_ok(&mut response)
+            EitherHandler::Sync(sync_handler) => sync_handler(request),
+            EitherHandler::Async(async_handler) => async_handler(request).await,
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes the response, telling the client whether the connection stays open. Returns `false`
+/// when the connection has to be closed afterwards, either because that's what was announced
+/// or because the body could not be written completely.
+pub(crate) async fn write_response(
+    stream: &mut Stream,
+    response: Response,
+    request: &Request,
+    keep_alive: bool,
+    write_timeout: Duration,
+) -> bool {
+    // TLS may hold back the end of the response until flushed. Closing flushes too, so
+    // only connections that stay open need it.
+    send_response(stream, response, request, keep_alive, write_timeout).await
+        && with_timeout(write_timeout, stream.flush()).await.is_ok()
+}
+
+async fn send_response(
+    stream: &mut Stream,
+    mut response: Response,
+    request: &Request,
+    keep_alive: bool,
+    write_timeout: Duration,
+) -> bool {
+    // The head says everything GET would, including the length of the body that isn't sent.
+    let head_only = request.method == "HEAD";
+    // HTTP/1.0 has no chunked encoding, a body of unknown length ends when the connection does.
+    let close_delimited = request.version == "HTTP/1.0"
+        && !head_only
+        && response
+            .stream
+            .as_ref()
+            .is_some_and(|body| body.length.is_none());
+    let keep_alive = keep_alive && !close_delimited;
+
+    response
+        .headers
+        .retain(|name, _| !name.eq_ignore_ascii_case("connection"));
+    if !keep_alive {
+        response
+            .headers
+            .insert("Connection".to_owned(), "close".to_owned());
+    } else if request.version == "HTTP/1.0" {
+        response
+            .headers
+            .insert("Connection".to_owned(), "keep-alive".to_owned());
+    }
+
+    let Some(body) = response.stream.take() else {
+        let head = handle_ok(&mut response, head_only);
+        return with_timeout(write_timeout, stream.write_all(head.as_bytes()))
+            .await
+            .is_ok()
+            && keep_alive;
+    };
+
+    let chunked = body.length.is_none() && !close_delimited;
+    let head = stream_head(&response, body.length, chunked);
+    if with_timeout(write_timeout, stream.write_all(head.as_bytes()))
+        .await
+        .is_err()
+    {
+        return false;
+    }
+    if head_only {
+        return keep_alive;
+    }
+
+    let mut chunks = match body.chunks {
+        Chunks::Stream(chunks) => chunks,
+        Chunks::File(range) => {
+            return match file::write_range(stream, range, write_timeout).await {
+                Ok(()) => keep_alive,
+                Err(err) => {
+                    if !is_disconnect(&err) {
+                        eprintln!("Error while sending file: {}", err);
+                    }
+                    false
+                }
+            };
+        }
+    };
+
+    // What is left of the announced `Content-Length`. Sending more or less than that would
+    // make the client read the next response from the wrong place.
+    let mut remaining = body.length;
+
+    // Chunks are pulled one at a time and each write is awaited before the next poll,
+    // so a slow client throttles the producer instead of the body piling up in memory.
+    while remaining != Some(0) {
+        let Some(chunk) = chunks.next().await else {
+            break;
+        };
+        let mut chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                // The head is already sent, so the only signal left is an incomplete body.
+                eprintln!("Error while streaming response body: {}", err);
+                return false;
+            }
+        };
+        if chunk.is_empty() {
+            continue;
+        }
+        if let Some(ref mut remaining) = remaining {
+            if chunk.len() as u64 > *remaining {
+                eprintln!("Response body stream is longer than its Content-Length, cutting it off");
+                chunk.truncate(*remaining as usize);
             }
-            EitherHandler::Async(async_handler) => {
-                let mut response = async_handler(request).await;
-                handle_ok(&mut response)
+            *remaining -= chunk.len() as u64;
+        }
+
+        // Flushed chunk by chunk, so events of a slow stream reach the client right away.
+        let written = with_timeout(write_timeout, async {
+            if chunked {
+                write_chunk(stream, &chunk).await?;
+            } else {
+                stream.write_all(&chunk).await?;
             }
+            stream.flush().await
+        })
+        .await;
+        if written.is_err() {
+            // The client went away, stop pulling from the stream.
+            return false;
         }
     }
+
+    if remaining.is_some_and(|remaining| remaining > 0) {
+        // Closing is the only way left to tell the client the body is incomplete.
+        eprintln!("Response body stream ended before its Content-Length");
+        return false;
+    }
+
+    if chunked
+        && with_timeout(write_timeout, stream.write_all(b"0\r\n\r\n"))
+            .await
+            .is_err()
+    {
+        return false;
+    }
+    keep_alive
+}
+
+async fn write_chunk(stream: &mut Stream, chunk: &[u8]) -> std::io::Result<()> {
+    stream
+        .write_all(format!("{:X}\r\n", chunk.len()).as_bytes())
+        .await?;
+    stream.write_all(chunk).await?;
+    stream.write_all(b"\r\n").await
 }
 
-fn handle_ok(response: &mut Response) -> String {
+fn format_cookies(response: &Response) -> String {
     let mut cookies = String::new();
-    if !response.cookies.is_empty() {
-        for (k, v) in response.cookies.iter() {
-            cookies += &format!(
-                "Set-Cookie: {}={}; HttpOnly; Path=/; Domain=example.com; Max-Age=3600\r\n",
-                k, v
-            );
-        }
+    for cookie in response.cookies.values() {
+        cookies += &format!("Set-Cookie: {}\r\n", cookie.to_header_value());
     }
+    cookies
+}
 
-    let mut content_disposition = String::new();
-    let body_content: String;
-    let content_length;
+fn format_headers(response: &Response) -> String {
+    let mut headers = String::new();
+    for (k, v) in response.headers.iter() {
+        headers += &format!("{}: {}\r\n", k, v);
+    }
+    headers
+}
 
-    if response.is_file {
-        let body_string = response.body.to_string();
-        let mut parts = body_string.splitn(2, ":");
+fn stream_head(response: &Response, length: Option<u64>, chunked: bool) -> String {
+    let framing = match length {
+        Some(length) => format!("Content-Length: {}\r\n", length),
+        None if chunked => "Transfer-Encoding: chunked\r\n".to_owned(),
+        None => String::new(),
+    };
 
-        let filename = parts.next().unwrap_or("file.txt").trim_matches('"');
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\n{}{}{}\r\n",
+        response.status,
+        response.content_type,
+        format_headers(response),
+        format_cookies(response),
+        framing
+    )
+}
 
-        let file_data = parts
-            .next()
-            .unwrap_or("")
-            .replace("\\r\\n", "\n")
-            .replace("\\\"", "\"")
-            .trim_end_matches("\"")
-            .into();
+fn handle_ok(response: &mut Response, head_only: bool) -> String {
+    let cookies = format_cookies(response);
 
-        content_disposition = format!(
-            "Content-Disposition: attachment; filename=\"{}\"\r\n",
-            filename
+    // 204 and 304 responses never have a body, not even an empty one with a length.
+    if response.status == StatusCode::NO_CONTENT || response.status == StatusCode::NOT_MODIFIED {
+        return format!(
+            "HTTP/1.1 {}\r\n{}{}\r\n",
+            response.status,
+            format_headers(response),
+            cookies
         );
-        body_content = file_data;
-        content_length = body_content.len();
-    } else {
-        body_content = response.body.as_str().unwrap_or("").to_string();
-        content_length = body_content.len();
     }
 
+    let body_content = response.body_text();
+    let content_length = body_content.len();
+    let body_content = if head_only { "" } else { body_content.as_str() };
+
     let new_response = format!(
-        "HTTP/1.1 {} {}\r\n{}Content-Type: {}\r\n{}Content-Length: {}\r\n\r\n{}",
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\n{}{}Content-Length: {}\r\n\r\n{}",
         response.status,
-        response.status_text,
-        content_disposition,
         response.content_type,
+        format_headers(response),
         cookies,
         content_length,
         body_content
@@ -245,22 +547,131 @@ fn handle_ok(response: &mut Response) -> String {
     new_response
 }
 
-fn handle_not_found() -> String {
-    format!(
-        "HTTP/1.1 404 Not Found\r\nContent-type: application/json\r\n\r\n{}",
-        serde_json::to_string(&json!({
+fn handle_not_found() -> Response {
+    Response::new().json(
+        json!({
             "error": "Page not found"
-        }))
-        .unwrap()
+        }),
+        StatusCode::NOT_FOUND,
     )
 }
 
-fn handle_method_not_allowed() -> String {
-    format!(
-        "HTTP/1.1 405 Method Not Allowed\r\nContent-type: application/json\r\n\r\n{}",
-        serde_json::to_string(&json!({
+fn handle_method_not_allowed() -> Response {
+    Response::new().json(
+        json!({
             "error": "This method is not allowed"
-        }))
-        .unwrap()
+        }),
+        StatusCode::METHOD_NOT_ALLOWED,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::request::parse_head;
+    use bytes::Bytes;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Writes the response to the request on a fresh connection. Returns whether the
+    /// connection could stay open, and everything the client received.
+    async fn send_to(request: &Request, response: Response) -> (bool, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut server = Stream::Tcp(listener.accept().await.unwrap().0);
+        let keep_alive =
+            write_response(&mut server, response, request, true, Duration::from_secs(5)).await;
+        drop(server);
+        let mut received = String::new();
+        client.read_to_string(&mut received).await.unwrap();
+        (keep_alive, received)
+    }
+
+    async fn send(response: Response) -> (bool, String) {
+        send_to(&Request::default(), response).await
+    }
+
+    fn stream_with_length(parts: Vec<&'static str>, length: u64) -> Response {
+        let chunks = futures::stream::iter(
+            parts
+                .into_iter()
+                .map(|part| Ok::<_, std::io::Error>(Bytes::from(part))),
+        );
+        Response::new().stream_with_length(chunks, length, 200)
+    }
+
+    #[tokio::test]
+    async fn streams_exactly_the_announced_length() {
+        let (keep_alive, received) = send(stream_with_length(vec!["hello", " world"], 11)).await;
+        assert!(keep_alive);
+        assert!(received.contains("Content-Length: 11\r\n"));
+        assert!(received.ends_with("\r\n\r\nhello world"));
+    }
+
+    #[tokio::test]
+    async fn cuts_off_streams_longer_than_announced() {
+        let (keep_alive, received) =
+            send(stream_with_length(vec!["hel", "lo world", "!"], 5)).await;
+        assert!(keep_alive);
+        assert!(received.ends_with("\r\n\r\nhello"));
+    }
+
+    #[tokio::test]
+    async fn closes_after_streams_shorter_than_announced() {
+        let (keep_alive, received) = send(stream_with_length(vec!["hello"], 20)).await;
+        assert!(!keep_alive);
+        assert!(received.ends_with("\r\n\r\nhello"));
+    }
+
+    #[tokio::test]
+    async fn chunks_streams_of_unknown_length() {
+        let chunks = futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from("hello"))]);
+        let (keep_alive, received) = send(Response::new().stream(chunks, 200)).await;
+        assert!(keep_alive);
+        assert!(received.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(received.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn head_is_answered_like_get_without_the_body() {
+        let mut router = Router::new();
+        router.get("/hello", |_| Response::new().text("hello", 200));
+        let head = parse_head("HEAD /hello HTTP/1.1").unwrap();
+        let response = router.handle_request(&head).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let (keep_alive, received) = send_to(&head, response).await;
+        assert!(keep_alive);
+        assert!(received.contains("Content-Length: 5\r\n"));
+        assert!(received.ends_with("\r\n\r\n"));
+
+        let streamed = send_to(&head, stream_with_length(vec!["hello"], 5)).await;
+        assert!(streamed.0);
+        assert!(streamed.1.ends_with("Content-Length: 5\r\n\r\n"));
+
+        let missing = parse_head("HEAD /missing HTTP/1.1").unwrap();
+        assert_eq!(
+            router.handle_request(&missing).await.status,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn head_serves_static_files() {
+        let root = std::env::temp_dir().join(format!("rust-express-head-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "static").unwrap();
+        let mut router = Router::new();
+        router.serve_static("/s", ServeStatic::new(&root));
+
+        let head = parse_head("HEAD /s/a.txt HTTP/1.1").unwrap();
+        let response = router.handle_request(&head).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let (keep_alive, received) = send_to(&head, response).await;
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(keep_alive);
+        assert!(received.contains("Content-Length: 6\r\n"));
+        assert!(received.ends_with("\r\n\r\n"));
+    }
+}
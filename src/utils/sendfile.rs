@@ -0,0 +1,63 @@
+use crate::utils::connection::with_timeout;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+// The kernel never transfers more than this in one call anyway.
+const MAX_COUNT: u64 = 0x7fff_f000;
+
+/// Copies `[offset, end)` of the file to the socket with `sendfile(2)`, so the bytes go from
+/// the page cache to the socket without passing through userspace. `offset` is advanced as
+/// data is sent. Returns `false` when the kernel can't sendfile from this file, leaving the
+/// rest to be copied the ordinary way.
+pub(crate) async fn send(
+    stream: &TcpStream,
+    file: &File,
+    offset: &mut u64,
+    end: u64,
+    write_timeout: Duration,
+) -> io::Result<bool> {
+    let (socket_fd, file_fd) = (stream.as_raw_fd(), file.as_raw_fd());
+    while *offset < end {
+        with_timeout(write_timeout, stream.writable()).await?;
+        let count = (end - *offset).min(MAX_COUNT) as usize;
+        let mut position = *offset as libc::off_t;
+        // try_io clears the readiness on WouldBlock, so the next writable() really waits.
+        let sent = stream.try_io(Interest::WRITABLE, || {
+            // Safety: both fds come from `stream` and `file`, which are borrowed for the whole
+            // call, so they stay open and can't be reused for something else. `position` is a
+            // local the kernel only reads and advances, and `count` bytes are written to the
+            // socket from the file, never into memory of ours.
+            let sent = unsafe { libc::sendfile(socket_fd, file_fd, &mut position, count) };
+            if sent < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(sent as u64)
+            }
+        });
+        match sent {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "File was truncated while being sent",
+                ))
+            }
+            Ok(sent) => *offset += sent,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) if is_unsupported(&err) => return Ok(false),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+// Some filesystems (and anything that isn't mmap-able) refuse sendfile outright.
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP)
+    )
+}
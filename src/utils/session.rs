@@ -0,0 +1,390 @@
+use crate::utils::cookie::{Cookie, SameSite};
+use crate::utils::cookie_jar::CookieJar;
+use crate::utils::request::Request;
+use crate::utils::response::Response;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use base64::prelude::*;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Everything a store needs to persist about one session. Timestamps are unix seconds.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub id: String,
+    pub data: HashMap<String, String>,
+    pub created_at: u64,
+    pub last_seen: u64,
+    /// When the session stops being valid, if any timeout is configured. Stores may drop it after this.
+    pub expires_at: Option<u64>,
+}
+
+impl SessionRecord {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Where session data lives between requests.
+///
+/// The token is what ends up (encrypted) in the session cookie: server side stores use the
+/// session id, the cookie store uses the serialized record itself.
+pub trait SessionStore: Send + Sync {
+    fn load<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Option<SessionRecord>>;
+    /// Persists the record and returns the token to send back in the session cookie.
+    fn save<'a>(&'a self, record: &'a SessionRecord) -> BoxFuture<'a, io::Result<String>>;
+    fn destroy<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// Keeps sessions in process memory. They are lost on restart and not shared between processes.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, SessionRecord>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Option<SessionRecord>> {
+        let record = self.sessions.lock().unwrap().get(token).cloned();
+        Box::pin(async move { record })
+    }
+
+    fn save<'a>(&'a self, record: &'a SessionRecord) -> BoxFuture<'a, io::Result<String>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        // Abandoned sessions are never destroyed explicitly, sweep them while we hold the lock.
+        let now = unix_now();
+        sessions.retain(|_, record| !record.is_expired(now));
+        sessions.insert(record.id.clone(), record.clone());
+        Box::pin(async move { Ok(record.id.clone()) })
+    }
+
+    fn destroy<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.sessions.lock().unwrap().remove(id);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Stores every session as a JSON file named after its id inside a directory.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        // Ids are generated as base64url, anything else must not reach the filesystem.
+        let valid = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        valid.then(|| self.dir.join(format!("{}.json", id)))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Option<SessionRecord>> {
+        Box::pin(async move {
+            let path = self.path(token)?;
+            let content = tokio::fs::read(&path).await.ok()?;
+            let record: SessionRecord = serde_json::from_slice(&content).ok()?;
+            if record.is_expired(unix_now()) {
+                let _ = tokio::fs::remove_file(&path).await;
+                return None;
+            }
+            Some(record)
+        })
+    }
+
+    fn save<'a>(&'a self, record: &'a SessionRecord) -> BoxFuture<'a, io::Result<String>> {
+        Box::pin(async move {
+            let path = self
+                .path(&record.id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid session id"))?;
+            tokio::fs::create_dir_all(&self.dir).await?;
+
+            // Write to a temporary file first so a crash never leaves a half written session behind.
+            let tmp = path.with_extension("json.tmp");
+            tokio::fs::write(&tmp, serde_json::to_vec(record)?).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+            Ok(record.id.clone())
+        })
+    }
+
+    fn destroy<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            match self.path(id) {
+                Some(path) => match tokio::fs::remove_file(path).await {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                    _ => Ok(()),
+                },
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+/// Keeps the whole session inside the (encrypted) cookie, so nothing is stored on the server.
+/// Browsers cap cookies at around 4KB, so only small sessions fit, and destroying a session
+/// cannot revoke copies of the cookie that were captured before.
+#[derive(Default)]
+pub struct CookieStore;
+
+impl CookieStore {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl SessionStore for CookieStore {
+    fn load<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Option<SessionRecord>> {
+        Box::pin(async move { serde_json::from_str(token).ok() })
+    }
+
+    fn save<'a>(&'a self, record: &'a SessionRecord) -> BoxFuture<'a, io::Result<String>> {
+        Box::pin(async move { Ok(serde_json::to_string(record)?) })
+    }
+
+    fn destroy<'a>(&'a self, _id: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[derive(Default)]
+struct SessionState {
+    record: SessionRecord,
+    is_new: bool,
+    modified: bool,
+    regenerate: bool,
+    destroyed: bool,
+}
+
+/// The session attached to a request. Cloning it gives another handle to the same session.
+#[derive(Clone, Default)]
+pub struct Session {
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl Session {
+    /// The session id. Empty for a new session until it is first saved.
+    pub fn id(&self) -> String {
+        self.state.lock().unwrap().record.id.clone()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state.lock().unwrap().record.data.get(key).cloned()
+    }
+
+    pub fn set(&self, key: &str, value: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.record.data.insert(key.to_owned(), value.to_owned());
+        state.modified = true;
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let value = state.record.data.remove(key);
+        state.modified |= value.is_some();
+        value
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.record.data.clear();
+        state.modified = true;
+    }
+
+    /// Moves the session data to a fresh id once the response is sent.
+    /// Call this after a login to prevent session fixation.
+    pub fn regenerate(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.regenerate = true;
+        state.modified = true;
+    }
+
+    /// Deletes the session from the store and clears the session cookie.
+    pub fn destroy(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.destroyed = true;
+        state.record.data.clear();
+    }
+
+    fn replace(&self, record: SessionRecord, is_new: bool) {
+        *self.state.lock().unwrap() = SessionState {
+            record,
+            is_new,
+            ..Default::default()
+        };
+    }
+}
+
+/// Session support for an `App`: which store to use, how the session cookie looks and when sessions expire.
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    jar: CookieJar,
+    cookie: Cookie,
+    idle_timeout: Option<Duration>,
+    absolute_timeout: Option<Duration>,
+}
+
+impl Sessions {
+    pub fn new(store: impl SessionStore + 'static, jar: CookieJar) -> Self {
+        Self {
+            store: Arc::new(store),
+            jar,
+            cookie: Cookie::new("sid", "").same_site(SameSite::Lax),
+            idle_timeout: None,
+            absolute_timeout: None,
+        }
+    }
+
+    /// Template for the session cookie. Its value is ignored, every other attribute is copied.
+    pub fn cookie(mut self, cookie: Cookie) -> Self {
+        self.cookie = cookie;
+        self
+    }
+
+    /// Expires sessions that have not been used for this long.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Expires sessions this long after they were created, no matter how active they are.
+    pub fn absolute_timeout(mut self, timeout: Duration) -> Self {
+        self.absolute_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) async fn load(&self, request: &Request) {
+        let now = unix_now();
+        let record = match request.private_cookie(&self.jar, &self.cookie.name) {
+            Some(token) => self.store.load(&token).await,
+            None => None,
+        };
+
+        match record {
+            Some(record) if !self.is_expired(&record, now) => {
+                request.session().replace(record, false)
+            }
+            expired => {
+                if let Some(record) = expired {
+                    let _ = self.store.destroy(&record.id).await;
+                }
+                let record = SessionRecord {
+                    created_at: now,
+                    last_seen: now,
+                    ..Default::default()
+                };
+                request.session().replace(record, true);
+            }
+        }
+    }
+
+    pub(crate) async fn commit(&self, request: &Request, response: &mut Response) {
+        let now = unix_now();
+        let (mut record, is_new, modified, regenerate, destroyed) = {
+            let state = request.session().state.lock().unwrap();
+            (
+                state.record.clone(),
+                state.is_new,
+                state.modified,
+                state.regenerate,
+                state.destroyed,
+            )
+        };
+
+        if destroyed {
+            if !record.id.is_empty() {
+                if let Err(err) = self.store.destroy(&record.id).await {
+                    eprintln!("Error while destroying session: {}", err);
+                }
+            }
+            if !is_new {
+                response.set_cookie(self.session_cookie("").expire());
+            }
+            return;
+        }
+
+        // Untouched new sessions are not worth a cookie, and untouched existing ones only
+        // need to be written again when their idle deadline has to move forward.
+        if !modified && (is_new || self.idle_timeout.is_none()) {
+            return;
+        }
+
+        if regenerate || record.id.is_empty() {
+            if !record.id.is_empty() {
+                let _ = self.store.destroy(&record.id).await;
+            }
+            record.id = generate_session_id();
+        }
+        record.last_seen = now;
+        record.expires_at = self.expires_at(&record);
+
+        match self.store.save(&record).await {
+            Ok(token) => {
+                let mut cookie = self.session_cookie(&token);
+                if let Some(expires_at) = record.expires_at {
+                    cookie = cookie.max_age(Duration::from_secs(expires_at.saturating_sub(now)));
+                }
+                response.set_private_cookie(&self.jar, cookie);
+                request.session().replace(record, false);
+            }
+            Err(err) => eprintln!("Error while saving session: {}", err),
+        }
+    }
+
+    fn session_cookie(&self, value: &str) -> Cookie {
+        let mut cookie = self.cookie.clone();
+        cookie.value = value.to_owned();
+        cookie
+    }
+
+    fn is_expired(&self, record: &SessionRecord, now: u64) -> bool {
+        record.is_expired(now)
+            || self
+                .idle_timeout
+                .is_some_and(|idle| record.last_seen + idle.as_secs() <= now)
+            || self
+                .absolute_timeout
+                .is_some_and(|absolute| record.created_at + absolute.as_secs() <= now)
+    }
+
+    fn expires_at(&self, record: &SessionRecord) -> Option<u64> {
+        let idle = self
+            .idle_timeout
+            .map(|idle| record.last_seen + idle.as_secs());
+        let absolute = self
+            .absolute_timeout
+            .map(|absolute| record.created_at + absolute.as_secs());
+        match (idle, absolute) {
+            (Some(idle), Some(absolute)) => Some(idle.min(absolute)),
+            (idle, absolute) => idle.or(absolute),
+        }
+    }
+}
+
+fn generate_session_id() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
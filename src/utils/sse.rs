@@ -0,0 +1,172 @@
+use bytes::Bytes;
+use futures::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+/// A single server-sent event frame.
+#[derive(Clone, Debug, Default)]
+pub struct Event {
+    event: Option<String>,
+    data: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn data(mut self, data: &str) -> Self {
+        self.data = Some(data.to_owned());
+        self
+    }
+
+    pub fn json(self, data: &serde_json::Value) -> Self {
+        self.data(&data.to_string())
+    }
+
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(single_line(event));
+        self
+    }
+
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_owned());
+        self
+    }
+
+    pub fn to_frame(&self) -> String {
+        let mut frame = String::new();
+        if let Some(ref comment) = self.comment {
+            for line in lines(comment) {
+                frame += &format!(": {}\n", line);
+            }
+        }
+        if let Some(ref event) = self.event {
+            frame += &format!("event: {}\n", event);
+        }
+        if let Some(ref id) = self.id {
+            frame += &format!("id: {}\n", id);
+        }
+        if let Some(retry) = self.retry {
+            frame += &format!("retry: {}\n", retry.as_millis());
+        }
+        if let Some(ref data) = self.data {
+            // Every line of a multi-line payload needs its own `data:` field.
+            for line in lines(data) {
+                frame += &format!("data: {}\n", line);
+            }
+        }
+        frame + "\n"
+    }
+}
+
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+/// Splits on every line ending the event stream format knows, `\r\n`, `\n` and a lone `\r`.
+/// Missing one lets a payload end its line early and add fields of its own, like `id:`.
+fn lines(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split("\r\n")
+        .flat_map(|line| line.split(['\r', '\n']))
+}
+
+/// Wraps a stream of events, injecting a keep-alive comment whenever nothing
+/// has been sent for a full interval. Ends as soon as the event stream ends.
+pub(crate) struct SseStream<S> {
+    events: Pin<Box<S>>,
+    keep_alive: Interval,
+    period: Duration,
+}
+
+impl<S> SseStream<S> {
+    pub(crate) fn new(events: S, period: Duration) -> Self {
+        let mut keep_alive = tokio::time::interval_at(Instant::now() + period, period);
+        keep_alive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            events: Box::pin(events),
+            keep_alive,
+            period,
+        }
+    }
+}
+
+impl<S> Stream for SseStream<S>
+where
+    S: Stream<Item = Event>,
+{
+    type Item = Result<Bytes, std::io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.events.as_mut().poll_next(cx) {
+            Poll::Ready(Some(event)) => {
+                let period = self.period;
+                self.keep_alive.reset_after(period);
+                return Poll::Ready(Some(Ok(Bytes::from(event.to_frame()))));
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => {}
+        }
+
+        match self.keep_alive.poll_tick(cx) {
+            Poll::Ready(_) => Poll::Ready(Some(Ok(Bytes::from_static(b": keep-alive\n\n")))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_all_fields() {
+        let frame = Event::new()
+            .comment("hi")
+            .event("update")
+            .id("7")
+            .retry(Duration::from_secs(3))
+            .data("payload")
+            .to_frame();
+        assert_eq!(
+            frame,
+            ": hi\nevent: update\nid: 7\nretry: 3000\ndata: payload\n\n"
+        );
+    }
+
+    #[test]
+    fn splits_data_on_every_line_ending() {
+        let frame = Event::new().data("a\nb\r\nc\rd").to_frame();
+        assert_eq!(frame, "data: a\ndata: b\ndata: c\ndata: d\n\n");
+        assert_eq!(Event::new().data("").to_frame(), "data: \n\n");
+    }
+
+    #[test]
+    fn payloads_cannot_inject_fields() {
+        let frame = Event::new().data("hello\rid: 999\revent: admin").to_frame();
+        assert_eq!(frame, "data: hello\ndata: id: 999\ndata: event: admin\n\n");
+        let frame = Event::new().comment("x\rdata: forged").to_frame();
+        assert_eq!(frame, ": x\n: data: forged\n\n");
+        let frame = Event::new()
+            .event("a\rdata: b")
+            .id("1\r\nretry: 0")
+            .to_frame();
+        assert_eq!(frame, "event: adata: b\nid: 1retry: 0\n\n");
+    }
+}
@@ -0,0 +1,200 @@
+use crate::utils::autoindex;
+use crate::utils::request::Request;
+use crate::utils::response::Response;
+use crate::utils::status::StatusCode;
+use std::path::PathBuf;
+
+/// What to do with requests for files or directories whose name starts with a dot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dotfiles {
+    Allow,
+    /// Answer with `403 Forbidden`.
+    Deny,
+    /// Pretend they don't exist.
+    Ignore,
+}
+
+/// Serves the files inside a directory, mounted on a router prefix with `Router::serve_static`.
+pub struct ServeStatic {
+    root: PathBuf,
+    index: Vec<String>,
+    dotfiles: Dotfiles,
+    autoindex: bool,
+}
+
+impl ServeStatic {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            index: vec!["index.html".to_owned()],
+            dotfiles: Dotfiles::Ignore,
+            autoindex: false,
+        }
+    }
+
+    /// Files tried, in order, when a directory is requested. Pass an empty list to disable.
+    pub fn index(mut self, index: &[&str]) -> Self {
+        self.index = index.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    pub fn dotfiles(mut self, dotfiles: Dotfiles) -> Self {
+        self.dotfiles = dotfiles;
+        self
+    }
+
+    /// List the contents of directories that have no index file, as HTML or, when the
+    /// client accepts it, JSON. Off by default.
+    pub fn autoindex(mut self, autoindex: bool) -> Self {
+        self.autoindex = autoindex;
+        self
+    }
+
+    /// Resolves the part of the path after the mount prefix. `None` means there is no such
+    /// file and the request should fall through to the router's 404.
+    pub(crate) async fn serve(&self, request: &Request, relative: &str) -> Option<Response> {
+        let segments = match split_path(relative) {
+            Some(segments) => segments,
+            None => return Some(Response::new().error("Bad Request", StatusCode::BAD_REQUEST)),
+        };
+
+        if segments.iter().any(|segment| segment.starts_with('.')) {
+            match self.dotfiles {
+                Dotfiles::Allow => {}
+                Dotfiles::Deny => {
+                    return Some(Response::new().error("Forbidden", StatusCode::FORBIDDEN))
+                }
+                Dotfiles::Ignore => return None,
+            }
+        }
+
+        let path = self.resolve(&segments).await?;
+        let metadata = tokio::fs::metadata(&path).await.ok()?;
+        if !metadata.is_dir() {
+            return Some(Response::new().file(path, "inline", StatusCode::OK));
+        }
+
+        // Relative links inside an index page only work when the directory URL ends with a slash.
+        if !request.route.ends_with('/') {
+            return Some(Response::new().redirect_permanent(&format!("{}/", request.route)));
+        }
+
+        for index in self.index.iter() {
+            let index_path = path.join(index);
+            if tokio::fs::metadata(&index_path)
+                .await
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                return Some(Response::new().file(index_path, "inline", StatusCode::OK));
+            }
+        }
+        if self.autoindex {
+            let root = tokio::fs::canonicalize(&self.root).await.ok()?;
+            return Some(autoindex::listing(request, &root, &path, self.dotfiles).await);
+        }
+        None
+    }
+
+    /// Joins the segments onto the root and makes sure the result, after following
+    /// symlinks, is still inside the root.
+    pub(crate) async fn resolve(&self, segments: &[String]) -> Option<PathBuf> {
+        let root = tokio::fs::canonicalize(&self.root).await.ok()?;
+        let mut path = root.clone();
+        for segment in segments {
+            path.push(segment);
+        }
+        let path = tokio::fs::canonicalize(&path).await.ok()?;
+        path.starts_with(&root).then_some(path)
+    }
+}
+
+/// Percent-decodes a URL path and splits it into segments, dropping empty and `.` segments.
+/// Returns `None` for paths trying to escape upwards or smuggle separators and NUL bytes.
+pub(crate) fn split_path(relative: &str) -> Option<Vec<String>> {
+    let mut segments = Vec::new();
+    for raw in relative.split('/') {
+        let segment = urlencoding::decode(raw).ok()?;
+        if segment.contains(['/', '\\', '\0']) || segment == ".." {
+            return None;
+        }
+        if segment.is_empty() || segment == "." {
+            continue;
+        }
+        // Windows would read `C:` as a drive, which is never a legitimate segment.
+        if cfg!(windows) && segment.contains(':') {
+            return None;
+        }
+        segments.push(segment.into_owned());
+    }
+    Some(segments)
+}
+
+/// Returns the remainder of the route when it falls under the mount prefix.
+pub(crate) fn strip_mount<'a>(prefix: &str, route: &'a str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    let rest = route.strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_and_decodes_segments() {
+        assert_eq!(split_path(""), Some(vec![]));
+        assert_eq!(split_path("/"), Some(vec![]));
+        assert_eq!(
+            split_path("/css//./app%20v2.css"),
+            Some(vec!["css".to_owned(), "app v2.css".to_owned()])
+        );
+        assert_eq!(
+            split_path("/a..b/.hidden"),
+            Some(vec!["a..b".to_owned(), ".hidden".to_owned()])
+        );
+    }
+
+    #[test]
+    fn refuses_traversal() {
+        for path in [
+            "/..",
+            "/css/../../etc/passwd",
+            "/%2e%2e/etc/passwd",
+            "/%2E%2E",
+            "/css%2f..%2f..%2fetc",
+            "/..%5cwindows",
+            "/a%00.html",
+            "/%ff",
+        ] {
+            assert_eq!(split_path(path), None, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn strips_mount_prefix_on_segment_boundaries() {
+        assert_eq!(strip_mount("/static", "/static"), Some(""));
+        assert_eq!(strip_mount("/static/", "/static/app.js"), Some("/app.js"));
+        assert_eq!(strip_mount("/", "/app.js"), Some("/app.js"));
+        assert_eq!(strip_mount("/static", "/staticfile"), None);
+        assert_eq!(strip_mount("/static", "/other"), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn resolves_only_inside_the_root() {
+        let dir = std::env::temp_dir().join(format!("rust-express-static-{}", std::process::id()));
+        let root = dir.join("public");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.js"), "").unwrap();
+        std::fs::write(dir.join("secret"), "").unwrap();
+        let _ = std::fs::remove_file(root.join("link"));
+        std::os::unix::fs::symlink(dir.join("secret"), root.join("link")).unwrap();
+
+        let serve = ServeStatic::new(&root);
+        let resolved = serve.resolve(&["app.js".to_owned()]).await;
+        assert_eq!(resolved, Some(root.join("app.js").canonicalize().unwrap()));
+        assert_eq!(serve.resolve(&["link".to_owned()]).await, None);
+        assert_eq!(serve.resolve(&["missing".to_owned()]).await, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}